@@ -5,128 +5,581 @@ use std::convert::TryInto;
 use std::sync::{Arc, Mutex};
 
 use gdbstub::arch::{Arch, RegId, Registers};
-use gdbstub::outputln;
-use gdbstub::target::ext::base::singlethread::{SingleThreadOps, StopReason};
-use gdbstub::target::ext::base::{BaseOps, ResumeAction};
+use gdbstub::common::Tid;
+use gdbstub::target::ext::base::multithread::{
+    MultiThreadOps, MultiThreadRangeStepping, MultiThreadRangeSteppingOps, MultiThreadReverseCont,
+    MultiThreadReverseContOps, MultiThreadReverseStep, MultiThreadReverseStepOps, ThreadStopReason,
+};
+use gdbstub::target::ext::base::singlethread::{
+    SingleThreadOps, SingleThreadRangeStepping, SingleThreadRangeSteppingOps,
+    SingleThreadReverseCont, SingleThreadReverseContOps, SingleThreadReverseStep,
+    SingleThreadReverseStepOps, StopReason,
+};
+use gdbstub::target::ext::base::{
+    BaseOps, GdbInterrupt, GdbInterruptNoAsync, ReplayLogPosition, ResumeAction,
+};
 #[allow(unused)]
 use gdbstub::target::ext::breakpoints::{
     Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, HwWatchpoint, HwWatchpointOps,
     SwBreakpoint, SwBreakpointOps, WatchKind,
 };
 use gdbstub::target::ext::monitor_cmd::{ConsoleOutput, MonitorCmd, MonitorCmdOps};
+use gdbstub::target::ext::target_description_xml_override::{
+    TargetDescriptionXmlOverride, TargetDescriptionXmlOverrideOps,
+};
 use gdbstub::target::{Target, TargetResult};
+use gdbstub::{output, outputln};
 
-use serde::Deserialize;
-
+use crate::breakpoint::HitInfo;
+use crate::gdb::{
+    common_monitor_cmd, lost_breakpoints, reset_and_resolve, reverse_until_stopped,
+    run_until_stopped, subscribe_console, subscribe_lifecycle, Capabilities, Lifecycle,
+    SoftwareBreakpoints, Stopped, Subscriptions,
+};
 use crate::{
-    breakpoint, event, event_stream, instance_registry, memory, resource, simulation,
-    simulation_time, step, FastModelIris,
+    breakpoint, event, event_stream, instance_registry, memory, resource, step, CallbackFlow,
+    CorneaError, FastModelIris, RwMode,
 };
 
-#[derive(Debug, Deserialize)]
-struct WatchTrigger {
-    #[serde(rename = "ACCESS_RW")]
-    kind: String,
-    #[serde(rename = "ACCESS_ADDR")]
-    addr: u64,
-    #[serde(rename = "BPT_ID")]
-    id: u64,
-}
-
 pub struct IrisGdbStub<'i> {
     pub iris: &'i mut FastModelIris,
+    /// The core that register and memory accesses go to
     pub instance_id: u32,
+    /// Every core of the platform. gdb's thread ids are indices into this,
+    /// plus one.
+    cores: Vec<u32>,
     sim: u32,
-    breakpoints: HashMap<u64, Vec<u64>>,
-    watchpoints: BTreeMap<u64, Vec<u64>>,
-    resources: Option<Vec<resource::ResourceInfo>>,
-    spaces: Option<Vec<memory::Space>>,
-    last_watch_trigger: Arc<Mutex<Option<WatchTrigger>>>,
+    // What the cores wrote to their console since `monitor console`
+    console: Arc<Mutex<String>>,
+    // Whether the simulation reset or shut down while gdb waited for it
+    lifecycle: Lifecycle,
+    // Breakpoints are set on every core; these hold the core and the Iris
+    // breakpoint id of each.
+    breakpoints: HashMap<u64, Vec<(u32, u64)>>,
+    // Watchpoints also keep the kind gdb asked for, to set them again after
+    // a reset
+    watchpoints: BTreeMap<u64, (WatchKind, Vec<(u32, u64)>)>,
+    // The breakpoint hit reported by Iris, kept until the simulation stops
+    last_hit: Arc<Mutex<Option<HitInfo>>>,
+    // The core gdb asked to step on the next resume
+    step_core: Option<u32>,
+    // The addresses gdb asked to keep stepping `step_core` through
+    step_range: Option<(u64, u64)>,
+    big_endian: bool,
+    // The byte order `set_big_endian` asked for, which outlasts a reset
+    forced_endian: Option<bool>,
+    // gdb's software breakpoints, when they are patched into memory
+    patches: SoftwareBreakpoints,
+    patch_breakpoints: bool,
+    // Set breakpoints in every memory space, not only the PC's
+    break_all_spaces: bool,
+    caps: Capabilities,
+    subs: Subscriptions,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Drop for IrisGdbStub<'_> {
+    fn drop(&mut self) {
+        self.patches.remove_all(self.iris);
+        self.subs.remove(self.iris);
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct GuestState {
-    pub regs: Vec<u64>,
+    pub x: [u64; 31],
+    pub sp: u64,
+    pub pc: u64,
+    pub cpsr: u32,
+    pub v: [u128; 32],
+    pub fpsr: u32,
+    pub fpcr: u32,
+    /// Whether gdb sees the registers in big-endian byte order
+    pub big_endian: bool,
 }
 
-impl Default for GuestState {
-    fn default() -> Self {
-        Self { regs: vec![0; 98] }
+impl GuestState {
+    /// The registers with the bytes of each reversed and the byte order
+    /// flipped, which serializes to the same bytes as `self`.
+    fn swap_bytes(&self) -> Self {
+        Self {
+            x: self.x.map(u64::swap_bytes),
+            sp: self.sp.swap_bytes(),
+            pc: self.pc.swap_bytes(),
+            cpsr: self.cpsr.swap_bytes(),
+            v: self.v.map(u128::swap_bytes),
+            fpsr: self.fpsr.swap_bytes(),
+            fpcr: self.fpcr.swap_bytes(),
+            big_endian: !self.big_endian,
+        }
+    }
+
+    /// The value of a register as the 64-bit words Iris uses, least
+    /// significant first.
+    fn words(&self, reg: &Register) -> Vec<u64> {
+        match reg {
+            Register::X(n) => vec![self.x[*n as usize]],
+            Register::SP => vec![self.sp],
+            Register::PC => vec![self.pc],
+            Register::XPSR => vec![self.cpsr as u64],
+            Register::V(n) => {
+                let v = self.v[*n as usize];
+                vec![v as u64, (v >> 64) as u64]
+            }
+            Register::FPSR => vec![self.fpsr as u64],
+            Register::FPCR => vec![self.fpcr as u64],
+        }
+    }
+
+    fn set_words(&mut self, reg: &Register, words: &[u64]) {
+        let word = |i: usize| words.get(i).copied().unwrap_or(0);
+        match reg {
+            Register::X(n) => self.x[*n as usize] = word(0),
+            Register::SP => self.sp = word(0),
+            Register::PC => self.pc = word(0),
+            Register::XPSR => self.cpsr = word(0) as u32,
+            Register::V(n) => self.v[*n as usize] = word(0) as u128 | (word(1) as u128) << 64,
+            Register::FPSR => self.fpsr = word(0) as u32,
+            Register::FPCR => self.fpcr = word(0) as u32,
+        }
     }
 }
 
 impl<'i> IrisGdbStub<'i> {
-    pub fn from_instance(iris: &'i mut FastModelIris, instance_id: u32) -> std::io::Result<Self> {
-        let sim = instance_registry::get_instance_by_name(
-            iris,
-            "framework.SimulationEngine".to_string(),
-        )?;
-        let source = event::source(iris, instance_id, "IRIS_BREAKPOINT_HIT".to_string())?;
-        let last_watch_trigger = Arc::new(Mutex::new(None));
-        let _stream = event_stream::create(
+    /// The memory space that the PC is in, which is where gdb expects its
+    /// memory accesses to go.
+    fn pc_memspace(&mut self) -> Result<u64, ()> {
+        let val =
+            resource::read_named(self.iris, self.instance_id, &["PC_MEMSPACE"]).map_err(|_| ())?;
+        val.get("PC_MEMSPACE")
+            .and_then(|words| words.first())
+            .copied()
+            .ok_or(())
+    }
+
+    /// A stub for core `instance_id`, running the simulation with the
+    /// model's usual simulation engine
+    pub fn from_instance(
+        iris: &'i mut FastModelIris,
+        instance_id: u32,
+    ) -> Result<Self, CorneaError> {
+        let sim = instance_registry::simulation_engine(iris, None)?;
+        Self::from_instance_and_engine(iris, instance_id, sim.id)
+    }
+
+    /// Like `from_instance`, running the simulation with engine `sim`, such
+    /// as one that `instance_registry::simulation_engine` found by name
+    pub fn from_instance_and_engine(
+        iris: &'i mut FastModelIris,
+        instance_id: u32,
+        sim: u32,
+    ) -> Result<Self, CorneaError> {
+        let caps = Capabilities::of(iris, instance_id, sim);
+        let mut stub = Self {
             iris,
-            Some(instance_id),
-            false,
-            iris.inst_id.unwrap(),
-            source.id,
-            false,
-            true,
-        )?;
-        let cb_last_watch_trigger = last_watch_trigger.clone();
-        iris.register_callback(
+            instance_id,
+            cores: Vec::new(),
+            breakpoints: HashMap::new(),
+            watchpoints: BTreeMap::new(),
+            sim,
+            console: Arc::default(),
+            lifecycle: Lifecycle::default(),
+            last_hit: Arc::default(),
+            step_core: None,
+            step_range: None,
+            big_endian: false,
+            forced_endian: None,
+            patches: SoftwareBreakpoints::default(),
+            patch_breakpoints: false,
+            break_all_spaces: false,
+            caps,
+            subs: Subscriptions::default(),
+        };
+        stub.subscribe()?;
+        stub.big_endian = stub.model_big_endian()?;
+        Ok(stub)
+    }
+
+    /// Find the cores and set up the event streams and callbacks that the
+    /// stub waits on
+    fn subscribe(&mut self) -> Result<(), CorneaError> {
+        self.lifecycle = subscribe_lifecycle(self.iris, self.sim, &mut self.subs)?;
+        self.cores = sibling_cores(self.iris, self.instance_id)?;
+        self.console = subscribe_console(self.iris, &self.cores, &mut self.subs)?;
+        let my_id = self.iris.instance_id().unwrap();
+        for core in &self.cores {
+            let source = event::source(self.iris, *core, "IRIS_BREAKPOINT_HIT".to_string())?;
+            let stream =
+                event_stream::subscribe_stopping(self.iris, Some(*core), my_id, source.id)?;
+            self.subs.streams.push((*core, stream));
+        }
+        let cb_last_hit = self.last_hit.clone();
+        let token = self.iris.register_callback(
             "ec_IRIS_BREAKPOINT_HIT".to_string(),
-            Box::new(move |mut params| {
-                if let Ok(ref mut trigger) = cb_last_watch_trigger.try_lock() {
-                    if let Some(watch_trigger) = params
-                        .as_object_mut()
-                        .and_then(|p| p.get_mut("fields"))
-                        .and_then(|f| serde_json::value::from_value(f.take()).ok())
-                    {
-                        **trigger = Some(watch_trigger);
-                    }
+            Box::new(move |params| {
+                if let Ok(ref mut hit) = cb_last_hit.try_lock() {
+                    **hit = breakpoint::decode_hit(params).ok();
                 }
-                Ok(())
+                Ok(CallbackFlow::Keep)
             }),
         );
-        Ok(Self {
-            iris,
-            instance_id,
-            breakpoints: HashMap::new(),
-            watchpoints: BTreeMap::new(),
-            sim: sim.id,
-            resources: None,
-            spaces: None,
-            last_watch_trigger,
-        })
+        self.subs.callbacks.push(token);
+        Ok(())
+    }
+
+    /// Whether the model says the core's memory is big-endian
+    fn model_big_endian(&mut self) -> Result<bool, CorneaError> {
+        match self.pc_memspace() {
+            Ok(space) => memory::is_big_endian(self.iris, self.instance_id, space),
+            Err(()) => Ok(false),
+        }
+    }
+
+    /// Reset the platform for `monitor reset`, then set up again what the
+    /// reset undid or may have renumbered: the cores, the event streams on
+    /// them, the model's breakpoints and watchpoints, and the software
+    /// breakpoints patched into the code.
+    fn reset(&mut self) -> Result<(), CorneaError> {
+        let patches = self.patches.remove_all(self.iris);
+        let mut breakpoints = Vec::new();
+        for (addr, ids) in std::mem::take(&mut self.breakpoints) {
+            for (core, id) in ids {
+                let _ = breakpoint::delete(self.iris, core, id);
+            }
+            breakpoints.push(addr);
+        }
+        let mut watchpoints = Vec::new();
+        for (addr, (kind, ids)) in std::mem::take(&mut self.watchpoints) {
+            for (core, id) in ids {
+                let _ = breakpoint::delete(self.iris, core, id);
+            }
+            watchpoints.push((addr, kind));
+        }
+        self.subs.remove(self.iris);
+        let unread = self
+            .console
+            .lock()
+            .map(|mut text| std::mem::take(&mut *text))
+            .unwrap_or_default();
+
+        reset_and_resolve(self.iris, &mut self.sim, &mut self.instance_id)?;
+        self.last_hit = Arc::default();
+        self.step_core = None;
+        self.step_range = None;
+        self.subscribe()?;
+        if let Ok(mut text) = self.console.lock() {
+            text.insert_str(0, &unread);
+        }
+        self.big_endian = match self.forced_endian {
+            Some(big_endian) => big_endian,
+            None => self.model_big_endian()?,
+        };
+
+        let mut lost = self
+            .patches
+            .insert_all(self.iris, self.instance_id, patches);
+        for addr in breakpoints {
+            if !matches!(self.add_hw_breakpoint(addr, 0), Ok(true)) {
+                lost.push(addr);
+            }
+        }
+        for (addr, kind) in watchpoints {
+            if !matches!(self.add_hw_watchpoint(addr, kind), Ok(true)) {
+                lost.push(addr);
+            }
+        }
+        if lost.is_empty() {
+            Ok(())
+        } else {
+            Err(lost_breakpoints(&lost))
+        }
+    }
+
+    /// Present registers to gdb in big-endian byte order, overriding what
+    /// the model reports for the core's memory.
+    pub fn set_big_endian(&mut self, big_endian: bool) {
+        self.big_endian = big_endian;
+        self.forced_endian = Some(big_endian);
+    }
+
+    /// Have gdb's software breakpoints patch a trap instruction into memory
+    /// rather than use the model's breakpoints, which are often few. See
+    /// `SoftwareBreakpoints` for what the model needs to do.
+    pub fn set_patch_breakpoints(&mut self, patch: bool) {
+        self.patch_breakpoints = patch && self.caps.memory_write;
+    }
+
+    /// Set breakpoints in every memory space of the cores, such as both
+    /// the secure and the non-secure one, rather than only in the space the
+    /// PC is in when gdb sets them. This catches code running in another
+    /// space at the same address, which is usually other code.
+    pub fn set_break_all_spaces(&mut self, all: bool) {
+        self.break_all_spaces = all;
+    }
+
+    /// The memory spaces to set a breakpoint in: the one the PC is in,
+    /// unless `set_break_all_spaces` asked for all of them or the model
+    /// does not say where the PC is.
+    fn breakpoint_spaces(&mut self) -> Result<Vec<u64>, ()> {
+        if !self.break_all_spaces {
+            if let Ok(space) = self.pc_memspace() {
+                return Ok(vec![space]);
+            }
+        }
+        // The cores are alike, so they share the selected core's spaces
+        let spaces = memory::cached_spaces(self.iris, self.instance_id).map_err(|_| ())?;
+        Ok(spaces.into_iter().map(|space| space.id).collect())
+    }
+
+    /// Direct register and memory accesses to the core with gdb thread id
+    /// `tid`.
+    fn select(&mut self, tid: Tid) -> Result<(), ()> {
+        self.instance_id = *self.cores.get(tid.get() - 1).ok_or(())?;
+        Ok(())
+    }
+
+    fn tid_of(&self, core: u32) -> Tid {
+        let index = self.cores.iter().position(|c| *c == core).unwrap_or(0);
+        Tid::new(index + 1).unwrap()
+    }
+
+    /// The PC of `core`
+    fn pc_of(&mut self, core: u32) -> Result<u64, ()> {
+        let val = resource::read_named(self.iris, core, &["PC"]).map_err(|_| ())?;
+        val.get("PC")
+            .and_then(|words| words.first())
+            .copied()
+            .ok_or(())
+    }
+
+    /// Disable the breakpoints that cores are stopped on, which would
+    /// trigger again as soon as the simulation runs, and return them for
+    /// `unpark_breakpoints`.
+    fn park_breakpoints(&mut self) -> Result<Vec<(u32, u64)>, ()> {
+        let mut parked = Vec::new();
+        for core in self.cores.clone() {
+            let pc = self.pc_of(core)?;
+            let ids = self.breakpoints.get(&pc).into_iter().flatten();
+            for (_, id) in ids.filter(|(c, _)| *c == core) {
+                parked.push((core, *id));
+            }
+        }
+        for (core, id) in &parked {
+            breakpoint::disable(self.iris, *core, *id).map_err(|_| ())?;
+        }
+        Ok(parked)
     }
+
+    fn unpark_breakpoints(&mut self, parked: &[(u32, u64)]) -> Result<(), ()> {
+        for (core, id) in parked {
+            breakpoint::enable(self.iris, *core, *id).map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    /// Step each core stopped on one of the `parked` breakpoints off it.
+    /// Stops early when the simulation stops for anything but the step, and
+    /// when a step hits a watchpoint, leaving the hit for `take_hit`.
+    fn step_off(
+        &mut self,
+        parked: &[(u32, u64)],
+        interrupt: &mut GdbInterruptNoAsync<'_>,
+    ) -> Result<Stopped, ()> {
+        let mut cores: Vec<u32> = parked.iter().map(|(core, _)| *core).collect();
+        cores.dedup();
+        for core in cores {
+            step::setup(self.iris, core, 1, step::Unit::Instruction).map_err(|_| ())?;
+            let stopped = run_until_stopped(self.iris, self.sim, &self.lifecycle, interrupt)
+                .map_err(|_| ())?;
+            if stopped != Stopped::Itself || self.has_hit() {
+                return Ok(stopped);
+            }
+        }
+        Ok(Stopped::Itself)
+    }
+
+    fn has_hit(&self) -> bool {
+        self.last_hit
+            .try_lock()
+            .map(|hit| hit.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Take the breakpoint hit reported since the last resume, returning the
+    /// core that hit it and, for a watchpoint, the kind of access and the
+    /// address gdb set the watchpoint on.
+    fn take_hit(&mut self) -> Option<(u32, Option<(WatchKind, u64)>)> {
+        let hit = self.last_hit.try_lock().ok()?.take()?;
+        let core = hit.inst.unwrap_or(self.instance_id);
+        let watch = hit.access().map(|(kind, trigger_addr)| {
+            let kind = match kind {
+                RwMode::Read => WatchKind::Read,
+                RwMode::Write => WatchKind::Write,
+                RwMode::ReadWrite => WatchKind::ReadWrite,
+            };
+            let addr = self.watchpoints.iter().find_map(|(k, (_, v))| {
+                if v.iter().any(|(_, id)| *id == hit.id) {
+                    Some(*k)
+                } else {
+                    None
+                }
+            });
+            (kind, addr.unwrap_or(trigger_addr))
+        });
+        Some((core, watch))
+    }
+
+    /// Step `core` one instruction, with the breakpoints the cores are
+    /// stopped on out of the way
+    fn step_once(
+        &mut self,
+        core: u32,
+        interrupt: &mut GdbInterruptNoAsync<'_>,
+    ) -> Result<Stopped, ()> {
+        let parked = self.park_breakpoints()?;
+        step::setup(self.iris, core, 1, step::Unit::Instruction).map_err(|_| ())?;
+        let stopped = run_until_stopped(self.iris, self.sim, &self.lifecycle, interrupt);
+        self.unpark_breakpoints(&parked)?;
+        stopped.map_err(|_| ())
+    }
+
+    /// Step `core` until its PC leaves `start..end`, without asking gdb after
+    /// every instruction. Stops early for whatever a step reports besides
+    /// being done, and at a breakpoint in the range, which gdb wants to hear
+    /// about.
+    fn step_through(
+        &mut self,
+        core: u32,
+        (start, end): (u64, u64),
+        interrupt: &mut GdbInterruptNoAsync<'_>,
+    ) -> Result<Stopped, ()> {
+        loop {
+            let stopped = self.step_once(core, interrupt)?;
+            if stopped != Stopped::Itself || self.has_hit() {
+                return Ok(stopped);
+            }
+            let pc = self.pc_of(core)?;
+            if !(start..end).contains(&pc)
+                || self.breakpoints.contains_key(&pc)
+                || self.patches.contains(pc)
+            {
+                return Ok(stopped);
+            }
+        }
+    }
+
+    /// What to tell gdb after a step: the watchpoint or breakpoint that the
+    /// stepped instruction hit, if any, as after a continue, so that gdb
+    /// does not miss it
+    fn step_stop_reason(&mut self) -> StopReason<u64> {
+        match self.take_hit() {
+            Some((_, Some((kind, addr)))) => StopReason::Watch { kind, addr },
+            Some((_, None)) => StopReason::HwBreak,
+            None => StopReason::DoneStep,
+        }
+    }
+
+    /// `step_stop_reason` for the multithreaded stub
+    fn thread_step_stop_reason(&mut self) -> ThreadStopReason<u64> {
+        match self.take_hit() {
+            Some((core, Some((kind, addr)))) => ThreadStopReason::Watch {
+                tid: self.tid_of(core),
+                kind,
+                addr,
+            },
+            Some((core, None)) => ThreadStopReason::HwBreak(self.tid_of(core)),
+            None => ThreadStopReason::DoneStep,
+        }
+    }
+
+    /// Run backwards, one instruction of `step_core` or else until a
+    /// breakpoint.
+    fn run_backwards(
+        &mut self,
+        step_core: Option<u32>,
+        intr: GdbInterrupt<'_>,
+    ) -> Result<Stopped, ()> {
+        let mut interrupt = intr.no_async();
+        // Forget hits from before, so that only this run's are reported
+        let _ = self.take_hit();
+        if let Some(core) = step_core {
+            step::setup(self.iris, core, 1, step::Unit::Instruction).map_err(|_| ())?;
+        }
+        reverse_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt).map_err(|_| ())
+    }
+}
+
+/// The cores of the platform that `instance_id` belongs to: those instances
+/// whose names differ from its name only in their numbers, such as
+/// `cluster1.cpu0` for `cluster0.cpu0`.
+fn sibling_cores(iris: &mut FastModelIris, instance_id: u32) -> Result<Vec<u32>, CorneaError> {
+    let me = instance_registry::get_instance_by_id(iris, instance_id)?;
+    let shape = |name: &str| {
+        name.chars()
+            .filter(|c| !c.is_ascii_digit())
+            .collect::<String>()
+    };
+    let my_shape = shape(&me.name);
+    let prefix = me.name.split('.').next().unwrap_or("").to_string();
+    let mut cores: Vec<_> = instance_registry::list_instances(iris, prefix)?
+        .into_iter()
+        .filter(|i| shape(&i.name) == my_shape)
+        .collect();
+    cores.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut cores: Vec<u32> = cores.into_iter().map(|i| i.id).collect();
+    if !cores.contains(&instance_id) {
+        cores.insert(0, instance_id);
+    }
+    Ok(cores)
 }
 
 impl Registers for GuestState {
     type ProgramCounter = u64;
     fn pc(&self) -> u64 {
-        self.regs[32]
+        self.pc
     }
+    // The layout of gdb's aarch64 core and fpu features
     fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
-        for reg in &self.regs {
-            for byte in reg.to_le_bytes().iter() {
+        if self.big_endian {
+            return self.swap_bytes().gdb_serialize(write_byte);
+        }
+        let mut write_bytes = |bytes: &[u8]| {
+            for byte in bytes {
                 write_byte(Some(*byte));
             }
+        };
+        for x in &self.x {
+            write_bytes(&x.to_le_bytes());
         }
-        write_byte(Some(0));
-        write_byte(Some(0));
-        write_byte(Some(0));
-        write_byte(Some(0));
+        write_bytes(&self.sp.to_le_bytes());
+        write_bytes(&self.pc.to_le_bytes());
+        write_bytes(&self.cpsr.to_le_bytes());
+        for v in &self.v {
+            write_bytes(&v.to_le_bytes());
+        }
+        write_bytes(&self.fpsr.to_le_bytes());
+        write_bytes(&self.fpcr.to_le_bytes());
     }
     fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
-        if bytes.len() % 8 != 0 {
+        if bytes.len() != 31 * 8 + 8 + 8 + 4 + 32 * 16 + 4 + 4 {
             return Err(());
         }
-        let mut regs = bytes
-            .chunks_exact(8)
-            .map(|c| u64::from_le_bytes(c.try_into().unwrap()));
-        for reg in &mut self.regs {
-            *reg = regs.next().ok_or(())?;
+        let mut rest = bytes;
+        let mut take = |len: usize| {
+            let (head, tail) = rest.split_at(len);
+            rest = tail;
+            head
+        };
+        for x in &mut self.x {
+            *x = u64::from_le_bytes(take(8).try_into().unwrap());
+        }
+        self.sp = u64::from_le_bytes(take(8).try_into().unwrap());
+        self.pc = u64::from_le_bytes(take(8).try_into().unwrap());
+        self.cpsr = u32::from_le_bytes(take(4).try_into().unwrap());
+        for v in &mut self.v {
+            *v = u128::from_le_bytes(take(16).try_into().unwrap());
         }
+        self.fpsr = u32::from_le_bytes(take(4).try_into().unwrap());
+        self.fpcr = u32::from_le_bytes(take(4).try_into().unwrap());
         Ok(())
     }
 }
@@ -137,19 +590,39 @@ pub enum Register {
     SP,
     PC,
     XPSR,
+    V(u8),
+    FPSR,
+    FPCR,
 }
 
 impl RegId for Register {
     fn from_raw_id(id: usize) -> Option<(Self, usize)> {
         use Register::*;
         Some(match id {
-            32 => PC,
-            33 => XPSR,
-            31 => SP,
-            id if id < 31 => X(id as u8),
+            id if id < 31 => (X(id as u8), 8),
+            31 => (SP, 8),
+            32 => (PC, 8),
+            33 => (XPSR, 4),
+            id if id < 66 => (V((id - 34) as u8), 16),
+            66 => (FPSR, 4),
+            67 => (FPCR, 4),
             _ => return None,
         })
-        .map(|r| (r, 0))
+    }
+}
+
+/// The register that a resource holds
+fn register_for(name: &str) -> Option<Register> {
+    match name {
+        "PC" => Some(Register::PC),
+        "SP" => Some(Register::SP),
+        "XPSR" => Some(Register::XPSR),
+        "CPSR" => Some(Register::XPSR),
+        "FPSR" => Some(Register::FPSR),
+        "FPCR" => Some(Register::FPCR),
+        x if x.starts_with('X') => x[1..].parse().ok().filter(|n| *n < 31).map(Register::X),
+        v if v.starts_with('V') => v[1..].parse().ok().filter(|n| *n < 32).map(Register::V),
+        _ => None,
     }
 }
 
@@ -157,95 +630,223 @@ impl<'i> Target for IrisGdbStub<'i> {
     type Arch = Armv8aArch;
     type Error = ();
     fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
-        BaseOps::SingleThread(self)
+        if self.cores.len() > 1 {
+            BaseOps::MultiThread(self)
+        } else {
+            BaseOps::SingleThread(self)
+        }
     }
 
-    fn breakpoints(&mut self) -> Option<BreakpointsOps<Self>> {
+    fn breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        if self.caps.breakpoints {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
         Some(self)
     }
 
-    fn monitor_cmd(&mut self) -> Option<MonitorCmdOps<Self>> {
+    fn target_description_xml_override(
+        &mut self,
+    ) -> Option<TargetDescriptionXmlOverrideOps<'_, Self>> {
         Some(self)
     }
 }
 
+/// Describes the registers in the order that `GuestState::gdb_serialize`
+/// writes them. The regnums are the raw ids of `Register::from_raw_id`.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>aarch64</architecture>
+  <feature name="org.gnu.gdb.aarch64.core">
+    <reg name="x0" bitsize="64" regnum="0"/>
+    <reg name="x1" bitsize="64"/>
+    <reg name="x2" bitsize="64"/>
+    <reg name="x3" bitsize="64"/>
+    <reg name="x4" bitsize="64"/>
+    <reg name="x5" bitsize="64"/>
+    <reg name="x6" bitsize="64"/>
+    <reg name="x7" bitsize="64"/>
+    <reg name="x8" bitsize="64"/>
+    <reg name="x9" bitsize="64"/>
+    <reg name="x10" bitsize="64"/>
+    <reg name="x11" bitsize="64"/>
+    <reg name="x12" bitsize="64"/>
+    <reg name="x13" bitsize="64"/>
+    <reg name="x14" bitsize="64"/>
+    <reg name="x15" bitsize="64"/>
+    <reg name="x16" bitsize="64"/>
+    <reg name="x17" bitsize="64"/>
+    <reg name="x18" bitsize="64"/>
+    <reg name="x19" bitsize="64"/>
+    <reg name="x20" bitsize="64"/>
+    <reg name="x21" bitsize="64"/>
+    <reg name="x22" bitsize="64"/>
+    <reg name="x23" bitsize="64"/>
+    <reg name="x24" bitsize="64"/>
+    <reg name="x25" bitsize="64"/>
+    <reg name="x26" bitsize="64"/>
+    <reg name="x27" bitsize="64"/>
+    <reg name="x28" bitsize="64"/>
+    <reg name="x29" bitsize="64"/>
+    <reg name="x30" bitsize="64"/>
+    <reg name="sp" bitsize="64" type="data_ptr"/>
+    <reg name="pc" bitsize="64" type="code_ptr"/>
+    <reg name="cpsr" bitsize="32"/>
+  </feature>
+  <feature name="org.gnu.gdb.aarch64.fpu">
+    <vector id="v2d" type="ieee_double" count="2"/>
+    <vector id="v2u" type="uint64" count="2"/>
+    <vector id="v4f" type="ieee_single" count="4"/>
+    <vector id="v4u" type="uint32" count="4"/>
+    <vector id="v8u" type="uint16" count="8"/>
+    <vector id="v16u" type="uint8" count="16"/>
+    <union id="aarch64v">
+      <field name="d" type="v2d"/>
+      <field name="s" type="v4f"/>
+      <field name="ud" type="v2u"/>
+      <field name="us" type="v4u"/>
+      <field name="h" type="v8u"/>
+      <field name="b" type="v16u"/>
+      <field name="q" type="uint128"/>
+    </union>
+    <reg name="v0" bitsize="128" type="aarch64v" regnum="34"/>
+    <reg name="v1" bitsize="128" type="aarch64v"/>
+    <reg name="v2" bitsize="128" type="aarch64v"/>
+    <reg name="v3" bitsize="128" type="aarch64v"/>
+    <reg name="v4" bitsize="128" type="aarch64v"/>
+    <reg name="v5" bitsize="128" type="aarch64v"/>
+    <reg name="v6" bitsize="128" type="aarch64v"/>
+    <reg name="v7" bitsize="128" type="aarch64v"/>
+    <reg name="v8" bitsize="128" type="aarch64v"/>
+    <reg name="v9" bitsize="128" type="aarch64v"/>
+    <reg name="v10" bitsize="128" type="aarch64v"/>
+    <reg name="v11" bitsize="128" type="aarch64v"/>
+    <reg name="v12" bitsize="128" type="aarch64v"/>
+    <reg name="v13" bitsize="128" type="aarch64v"/>
+    <reg name="v14" bitsize="128" type="aarch64v"/>
+    <reg name="v15" bitsize="128" type="aarch64v"/>
+    <reg name="v16" bitsize="128" type="aarch64v"/>
+    <reg name="v17" bitsize="128" type="aarch64v"/>
+    <reg name="v18" bitsize="128" type="aarch64v"/>
+    <reg name="v19" bitsize="128" type="aarch64v"/>
+    <reg name="v20" bitsize="128" type="aarch64v"/>
+    <reg name="v21" bitsize="128" type="aarch64v"/>
+    <reg name="v22" bitsize="128" type="aarch64v"/>
+    <reg name="v23" bitsize="128" type="aarch64v"/>
+    <reg name="v24" bitsize="128" type="aarch64v"/>
+    <reg name="v25" bitsize="128" type="aarch64v"/>
+    <reg name="v26" bitsize="128" type="aarch64v"/>
+    <reg name="v27" bitsize="128" type="aarch64v"/>
+    <reg name="v28" bitsize="128" type="aarch64v"/>
+    <reg name="v29" bitsize="128" type="aarch64v"/>
+    <reg name="v30" bitsize="128" type="aarch64v"/>
+    <reg name="v31" bitsize="128" type="aarch64v"/>
+    <reg name="fpsr" bitsize="32"/>
+    <reg name="fpcr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+impl TargetDescriptionXmlOverride for IrisGdbStub<'_> {
+    fn target_description_xml(&self) -> &str {
+        TARGET_XML
+    }
+}
+
 impl SingleThreadOps for IrisGdbStub<'_> {
+    fn support_resume_range_step(&mut self) -> Option<SingleThreadRangeSteppingOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_reverse_step(&mut self) -> Option<SingleThreadReverseStepOps<'_, Self>> {
+        if self.caps.reverse {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn support_reverse_cont(&mut self) -> Option<SingleThreadReverseContOps<'_, Self>> {
+        if self.caps.reverse {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
     fn read_registers(&mut self, regs: &mut GuestState) -> TargetResult<(), Self> {
-        if self.resources.is_none() {
-            let resources =
-                resource::get_list(&mut self.iris, self.instance_id, None, None).map_err(|_| ())?;
-            self.resources = Some(resources);
-        };
-        for res in self.resources.as_ref().unwrap() {
-            let regnum = match res.name.as_str() {
-                "PC" => 32,
-                "SP" => 31,
-                "XPSR" => 33,
-                "CPSR" => 33,
-                x if x.starts_with("X") => {
-                    if let Ok(regnum) = x[1..].parse() {
-                        regnum
-                    } else {
-                        continue;
-                    }
-                }
-                _ => continue,
-            };
-            let val =
-                resource::read(&mut self.iris, self.instance_id, vec![res.id]).map_err(|_| ())?;
-            if !val.data.is_empty() {
-                regs.regs[regnum] = val.data[0]
+        regs.big_endian = self.big_endian;
+        let names: Vec<_> = resource::cached_list(self.iris, self.instance_id)
+            .map_err(|_| ())?
+            .into_iter()
+            .filter(|res| register_for(&res.name).is_some())
+            .map(|res| res.name)
+            .collect();
+        let val = resource::read_named(self.iris, self.instance_id, &names).map_err(|_| ())?;
+        for (name, words) in val {
+            if let Some(reg) = register_for(&name) {
+                regs.set_words(&reg, &words);
             }
         }
         Ok(())
     }
 
     fn read_addrs(&mut self, start_addr: u64, data: &mut [u8]) -> TargetResult<(), Self> {
-        if self.resources.is_none() {
-            let resources =
-                resource::get_list(&mut self.iris, self.instance_id, None, None).map_err(|_| ())?;
-            self.resources = Some(resources);
-        };
-        let mut memspace_res = Err(());
-        for res in self.resources.as_ref().unwrap() {
-            match res.name.as_str() {
-                "PC_MEMSPACE" => memspace_res = Ok(res.id),
-                _ => (),
-            }
-        }
-        let memspace_res = memspace_res?;
-        let memspace = *resource::read(&mut self.iris, self.instance_id, vec![memspace_res])?
-            .data
-            .get(0)
-            .ok_or(())?;
-        let mem = memory::read(
-            &mut self.iris,
+        let memspace = self.pc_memspace()?;
+        let mem = memory::read_bytes(
+            self.iris,
             self.instance_id,
             memspace,
-            start_addr as u64,
-            1,
+            start_addr,
             data.len() as u64,
         )
         .map_err(|_| ())?;
-        for (offset, byte) in mem
-            .data
-            .into_iter()
-            .map(|u| u.to_le_bytes())
-            .flatten()
-            .enumerate()
-        {
-            if data.len() > offset {
-                data[offset] = byte;
-            }
-        }
+        data.copy_from_slice(&mem);
+        self.patches.hide(start_addr, data);
         Ok(())
     }
 
-    fn write_addrs(&mut self, _: u64, _: &[u8]) -> TargetResult<(), Self> {
+    fn write_addrs(&mut self, start_addr: u64, data: &[u8]) -> TargetResult<(), Self> {
+        let memspace = self.pc_memspace()?;
+        let data = self.patches.write_through(start_addr, data);
+        memory::write_bytes(self.iris, self.instance_id, memspace, start_addr, &data)
+            .map_err(|_| ())?;
         Ok(())
     }
-    fn write_registers(&mut self, _: &GuestState) -> TargetResult<(), Self> {
-        // We don't support writing
+    fn write_registers(&mut self, regs: &GuestState) -> TargetResult<(), Self> {
+        // gdbstub reads gdb's registers into a little-endian state
+        let regs = if self.big_endian && !regs.big_endian {
+            &regs.swap_bytes()
+        } else {
+            regs
+        };
+        // Only write what gdb changed, as some registers have side effects
+        let mut current = GuestState::default();
+        SingleThreadOps::read_registers(self, &mut current)?;
+        let mut ids = Vec::new();
+        let mut data = Vec::new();
+        for res in resource::cached_list(self.iris, self.instance_id).map_err(|_| ())? {
+            let reg = match register_for(&res.name) {
+                Some(reg) => reg,
+                None => continue,
+            };
+            let words = regs.words(&reg);
+            if res.rw_mode != Some(RwMode::Read) && current.words(&reg) != words {
+                ids.push(res.id);
+                data.extend(words);
+            }
+        }
+        if !ids.is_empty() {
+            let res = resource::write(self.iris, self.instance_id, ids, data).map_err(|_| ())?;
+            if res.error.is_some() {
+                return Err(().into());
+            }
+        }
         Ok(())
     }
 
@@ -255,42 +856,32 @@ impl SingleThreadOps for IrisGdbStub<'_> {
         intr: gdbstub::target::ext::base::GdbInterrupt<'_>,
     ) -> Result<StopReason<u64>, ()> {
         let mut interrupt = intr.no_async();
-        if act == ResumeAction::Step {
-            step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction).map_err(|_| ())?
-        }
         if act == ResumeAction::Step || act == ResumeAction::Continue {
-            simulation_time::run(self.iris, self.sim).map_err(|_| ())?;
-            while simulation_time::get(self.iris, self.sim)
-                .map_err(|_| ())?
-                .running
-            {
-                if interrupt.pending() {
-                    simulation_time::stop(self.iris, self.sim).map_err(|_| ())?;
-                    return Ok(StopReason::GdbInterrupt);
+            let parked = self.park_breakpoints()?;
+            let mut stopped = Stopped::Itself;
+            if act == ResumeAction::Continue {
+                stopped = self.step_off(&parked, &mut interrupt)?;
+                self.unpark_breakpoints(&parked)?;
+            }
+            if stopped == Stopped::Itself && !self.has_hit() {
+                if act == ResumeAction::Step {
+                    step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction)
+                        .map_err(|_| ())?
                 }
-                std::thread::sleep(std::time::Duration::from_millis(100));
+                stopped = run_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt)
+                    .map_err(|_| ())?;
             }
             if act == ResumeAction::Step {
-                return Ok(StopReason::DoneStep);
+                self.unpark_breakpoints(&parked)?;
+            }
+            if let Some(reason) = stopped.stop_reason() {
+                return Ok(reason);
+            }
+            if act == ResumeAction::Step {
+                return Ok(self.step_stop_reason());
             } else {
-                if let Ok(mut locked) = self.last_watch_trigger.try_lock() {
-                    if let Some(trigger) = locked.take() {
-                        let kind = match trigger.kind.as_str() {
-                            "r" => WatchKind::Read,
-                            "w" => WatchKind::Write,
-                            "rw" => WatchKind::ReadWrite,
-                            _ => return Ok(StopReason::HwBreak),
-                        };
-                        let addr = self.watchpoints.iter().find_map(|(k, v)| {
-                            if v.contains(&trigger.id) {
-                                Some(*k)
-                            } else {
-                                None
-                            }
-                        });
-                        let addr = addr.unwrap_or(trigger.addr);
-                        return Ok(StopReason::Watch { kind, addr });
-                    }
+                if let Some((_, Some((kind, addr)))) = self.take_hit() {
+                    return Ok(StopReason::Watch { kind, addr });
                 }
                 return Ok(StopReason::HwBreak);
             }
@@ -299,26 +890,236 @@ impl SingleThreadOps for IrisGdbStub<'_> {
     }
 }
 
+impl SingleThreadRangeStepping for IrisGdbStub<'_> {
+    fn resume_range_step(
+        &mut self,
+        start: u64,
+        end: u64,
+        intr: GdbInterrupt<'_>,
+    ) -> Result<StopReason<u64>, ()> {
+        let mut interrupt = intr.no_async();
+        let stopped = self.step_through(self.instance_id, (start, end), &mut interrupt)?;
+        if let Some(reason) = stopped.stop_reason() {
+            return Ok(reason);
+        }
+        Ok(self.step_stop_reason())
+    }
+}
+
+impl MultiThreadOps for IrisGdbStub<'_> {
+    fn support_range_step(&mut self) -> Option<MultiThreadRangeSteppingOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_reverse_step(&mut self) -> Option<MultiThreadReverseStepOps<'_, Self>> {
+        if self.caps.reverse {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn support_reverse_cont(&mut self) -> Option<MultiThreadReverseContOps<'_, Self>> {
+        if self.caps.reverse {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn resume(
+        &mut self,
+        default_resume_action: ResumeAction,
+        intr: gdbstub::target::ext::base::GdbInterrupt<'_>,
+    ) -> Result<ThreadStopReason<u64>, ()> {
+        let mut interrupt = intr.no_async();
+        // Iris runs all cores together, so only the core being stepped
+        // behaves differently from the rest.
+        let step_core = match default_resume_action {
+            ResumeAction::Step => Some(self.instance_id),
+            ResumeAction::Continue => self.step_core.take(),
+            _ => return Err(()),
+        };
+        if let (Some(core), Some(range)) = (step_core, self.step_range.take()) {
+            let stopped = self.step_through(core, range, &mut interrupt)?;
+            if let Some(reason) = stopped.thread_stop_reason() {
+                return Ok(reason);
+            }
+            return Ok(self.thread_step_stop_reason());
+        }
+        // Step off any breakpoints the cores are stopped on first, or they
+        // would trigger again at once
+        let parked = self.park_breakpoints()?;
+        let mut stopped = Stopped::Itself;
+        if step_core.is_none() {
+            stopped = self.step_off(&parked, &mut interrupt)?;
+            self.unpark_breakpoints(&parked)?;
+        }
+        if stopped == Stopped::Itself && !self.has_hit() {
+            if let Some(core) = step_core {
+                step::setup(self.iris, core, 1, step::Unit::Instruction).map_err(|_| ())?
+            }
+            stopped = run_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt)
+                .map_err(|_| ())?;
+        }
+        if step_core.is_some() {
+            self.unpark_breakpoints(&parked)?;
+        }
+        if let Some(reason) = stopped.thread_stop_reason() {
+            return Ok(reason);
+        }
+        match (step_core, self.thread_step_stop_reason()) {
+            // A continue only stops at a breakpoint, even when no hit came
+            (None, ThreadStopReason::DoneStep) => {
+                Ok(ThreadStopReason::HwBreak(self.tid_of(self.instance_id)))
+            }
+            (_, reason) => Ok(reason),
+        }
+    }
+
+    fn clear_resume_actions(&mut self) -> Result<(), ()> {
+        self.step_core = None;
+        self.step_range = None;
+        Ok(())
+    }
+
+    fn set_resume_action(&mut self, tid: Tid, action: ResumeAction) -> Result<(), ()> {
+        match action {
+            ResumeAction::Step => {
+                let core = *self.cores.get(tid.get() - 1).ok_or(())?;
+                self.step_core = Some(core);
+                Ok(())
+            }
+            ResumeAction::Continue => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    fn read_registers(&mut self, regs: &mut GuestState, tid: Tid) -> TargetResult<(), Self> {
+        self.select(tid)?;
+        SingleThreadOps::read_registers(self, regs)
+    }
+
+    fn write_registers(&mut self, regs: &GuestState, tid: Tid) -> TargetResult<(), Self> {
+        self.select(tid)?;
+        SingleThreadOps::write_registers(self, regs)
+    }
+
+    fn read_addrs(&mut self, start_addr: u64, data: &mut [u8], tid: Tid) -> TargetResult<(), Self> {
+        self.select(tid)?;
+        SingleThreadOps::read_addrs(self, start_addr, data)
+    }
+
+    fn write_addrs(&mut self, start_addr: u64, data: &[u8], tid: Tid) -> TargetResult<(), Self> {
+        self.select(tid)?;
+        SingleThreadOps::write_addrs(self, start_addr, data)
+    }
+
+    fn list_active_threads(&mut self, thread_is_active: &mut dyn FnMut(Tid)) -> Result<(), ()> {
+        for index in 0..self.cores.len() {
+            thread_is_active(Tid::new(index + 1).unwrap());
+        }
+        Ok(())
+    }
+}
+
+impl MultiThreadRangeStepping for IrisGdbStub<'_> {
+    fn set_resume_action_range_step(&mut self, tid: Tid, start: u64, end: u64) -> Result<(), ()> {
+        self.step_core = Some(*self.cores.get(tid.get() - 1).ok_or(())?);
+        self.step_range = Some((start, end));
+        Ok(())
+    }
+}
+
+impl SingleThreadReverseStep for IrisGdbStub<'_> {
+    fn reverse_step(&mut self, intr: GdbInterrupt<'_>) -> Result<StopReason<u64>, ()> {
+        if let Some(reason) = self
+            .run_backwards(Some(self.instance_id), intr)?
+            .stop_reason()
+        {
+            return Ok(reason);
+        }
+        Ok(self.step_stop_reason())
+    }
+}
+
+impl SingleThreadReverseCont for IrisGdbStub<'_> {
+    fn reverse_cont(&mut self, intr: GdbInterrupt<'_>) -> Result<StopReason<u64>, ()> {
+        if let Some(reason) = self.run_backwards(None, intr)?.stop_reason() {
+            return Ok(reason);
+        }
+        // Stopping without a breakpoint means the recording ran out
+        match self.take_hit() {
+            Some((_, Some((kind, addr)))) => Ok(StopReason::Watch { kind, addr }),
+            Some((_, None)) => Ok(StopReason::HwBreak),
+            None => Ok(StopReason::ReplayLog(ReplayLogPosition::Begin)),
+        }
+    }
+}
+
+impl MultiThreadReverseStep for IrisGdbStub<'_> {
+    fn reverse_step(
+        &mut self,
+        tid: Tid,
+        intr: GdbInterrupt<'_>,
+    ) -> Result<ThreadStopReason<u64>, ()> {
+        let core = *self.cores.get(tid.get() - 1).ok_or(())?;
+        if let Some(reason) = self.run_backwards(Some(core), intr)?.thread_stop_reason() {
+            return Ok(reason);
+        }
+        Ok(self.thread_step_stop_reason())
+    }
+}
+
+impl MultiThreadReverseCont for IrisGdbStub<'_> {
+    fn reverse_cont(&mut self, intr: GdbInterrupt<'_>) -> Result<ThreadStopReason<u64>, ()> {
+        if let Some(reason) = self.run_backwards(None, intr)?.thread_stop_reason() {
+            return Ok(reason);
+        }
+        // Stopping without a breakpoint means the recording ran out
+        match self.take_hit() {
+            Some((core, Some((kind, addr)))) => Ok(ThreadStopReason::Watch {
+                tid: self.tid_of(core),
+                kind,
+                addr,
+            }),
+            Some((core, None)) => Ok(ThreadStopReason::HwBreak(self.tid_of(core))),
+            None => Ok(ThreadStopReason::ReplayLog(ReplayLogPosition::Begin)),
+        }
+    }
+}
+
 impl<'i> Breakpoints for IrisGdbStub<'i> {
-    fn hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
+    fn hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
         Some(self)
     }
 
-    fn hw_watchpoint(&mut self) -> Option<HwWatchpointOps<Self>> {
+    fn hw_watchpoint(&mut self) -> Option<HwWatchpointOps<'_, Self>> {
         Some(self)
     }
 
-    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<Self>> {
+    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
         Some(self)
     }
 }
+
+/// BRK #0
+const TRAP: [u8; 4] = [0x00, 0x00, 0x20, 0xd4];
+
 impl<'i> SwBreakpoint for IrisGdbStub<'i> {
     fn add_sw_breakpoint(
         &mut self,
         addr: <Self::Arch as Arch>::Usize,
         k: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
-        self.add_hw_breakpoint(addr, k)
+        if !self.patch_breakpoints {
+            return self.add_hw_breakpoint(addr, k);
+        }
+        let space = self.pc_memspace()?;
+        Ok(self
+            .patches
+            .insert(self.iris, self.instance_id, space, addr, &TRAP)
+            .is_ok())
     }
 
     fn remove_sw_breakpoint(
@@ -326,7 +1127,11 @@ impl<'i> SwBreakpoint for IrisGdbStub<'i> {
         addr: <Self::Arch as Arch>::Usize,
         k: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
-        self.remove_hw_breakpoint(addr, k)
+        match self.patches.remove(self.iris, addr) {
+            Ok(true) => Ok(true),
+            Ok(false) => self.remove_hw_breakpoint(addr, k),
+            Err(_) => Ok(false),
+        }
     }
 }
 
@@ -339,24 +1144,16 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
         if self.breakpoints.contains_key(&addr) {
             return Ok(true);
         }
-        if self.spaces.is_none() {
-            let spaces = memory::spaces(self.iris, self.instance_id)?;
-            self.spaces = Some(spaces);
-        };
-        let Self {
-            spaces,
-            iris,
-            instance_id,
-            ..
-        } = self;
-        let store: Vec<u64> = spaces
-            .as_ref()
-            .unwrap()
-            .iter()
-            .filter_map(|space| {
-                breakpoint::code(iris, *instance_id, addr as u64, None, space.id, false).ok()
-            })
-            .collect();
+        let spaces = self.breakpoint_spaces()?;
+        let Self { iris, cores, .. } = self;
+        let mut store = Vec::new();
+        for core in cores.iter() {
+            for space in &spaces {
+                if let Ok(id) = breakpoint::code(iris, *core, addr, None, *space, false) {
+                    store.push((*core, id));
+                }
+            }
+        }
 
         if store.is_empty() {
             Ok(false)
@@ -371,8 +1168,8 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
         _: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
         if let Entry::Occupied(ent) = self.breakpoints.entry(addr) {
-            for bkpt in ent.get() {
-                if let Err(_) = breakpoint::delete(self.iris, self.instance_id, *bkpt) {
+            for (core, bkpt) in ent.get() {
+                if breakpoint::delete(self.iris, *core, *bkpt).is_err() {
                     return Ok(false);
                 }
             }
@@ -382,13 +1179,12 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
     }
 }
 
-fn kind_to_str(kind: WatchKind) -> String {
+fn kind_to_mode(kind: WatchKind) -> RwMode {
     match kind {
-        WatchKind::Read => "r",
-        WatchKind::Write => "w",
-        WatchKind::ReadWrite => "rw",
+        WatchKind::Read => RwMode::Read,
+        WatchKind::Write => RwMode::Write,
+        WatchKind::ReadWrite => RwMode::ReadWrite,
     }
-    .to_string()
 }
 
 impl<'i> HwWatchpoint for IrisGdbStub<'i> {
@@ -400,40 +1196,31 @@ impl<'i> HwWatchpoint for IrisGdbStub<'i> {
         if self.watchpoints.contains_key(&addr) {
             return Ok(true);
         }
-        if self.spaces.is_none() {
-            let spaces = memory::spaces(self.iris, self.instance_id)?;
-            self.spaces = Some(spaces);
-        };
-        let Self {
-            spaces,
-            iris,
-            instance_id,
-            ..
-        } = self;
-        let store: Vec<u64> = spaces
-            .as_ref()
-            .unwrap()
-            .iter()
-            .filter_map(|space| {
-                breakpoint::set(
+        let spaces = memory::cached_spaces(self.iris, self.instance_id).map_err(|_| ())?;
+        let Self { iris, cores, .. } = self;
+        let mut store = Vec::new();
+        for core in cores.iter() {
+            for space in &spaces {
+                if let Ok(id) = breakpoint::set(
                     iris,
-                    *instance_id,
-                    addr as u64,
-                    Some(kind_to_str(kind)),
+                    *core,
+                    addr,
+                    Some(kind_to_mode(kind)),
                     None,
                     Some(space.id),
                     crate::breakpoint::Type::Data,
                     false,
                     false,
-                )
-                .ok()
-            })
-            .collect();
+                ) {
+                    store.push((*core, id));
+                }
+            }
+        }
 
         if store.is_empty() {
             Ok(false)
         } else {
-            self.watchpoints.insert(addr, store);
+            self.watchpoints.insert(addr, (kind, store));
             Ok(true)
         }
     }
@@ -443,8 +1230,8 @@ impl<'i> HwWatchpoint for IrisGdbStub<'i> {
         _kind: WatchKind,
     ) -> TargetResult<bool, Self> {
         if let BTreeEntry::Occupied(ent) = self.watchpoints.entry(addr) {
-            for bkpt in ent.get() {
-                if let Err(_) = breakpoint::delete(self.iris, self.instance_id, *bkpt) {
+            for (core, bkpt) in &ent.get().1 {
+                if breakpoint::delete(self.iris, *core, *bkpt).is_err() {
                     return Ok(false);
                 }
             }
@@ -457,12 +1244,22 @@ impl<'i> HwWatchpoint for IrisGdbStub<'i> {
 impl<'i> MonitorCmd for IrisGdbStub<'i> {
     fn handle_monitor_cmd(&mut self, cmd: &[u8], mut out: ConsoleOutput<'_>) -> Result<(), ()> {
         match String::from_utf8_lossy(cmd).borrow() {
-            "reset" => {
-                simulation::reset(self.iris, self.sim, false).map_err(|_| ())?;
-                simulation::wait(self.iris, self.sim).map_err(|_| ())?;
+            "console" => {
+                let text = std::mem::take(&mut *self.console.lock().map_err(|_| ())?);
+                output!(out, "{}", text);
+            }
+            c if c.trim() == "reset" => {
+                if let Err(e) = self.reset() {
+                    outputln!(out, "{}", e);
+                }
             }
             c => {
-                outputln!(out, "Monitor command {} not supported", c);
+                let space = self.pc_memspace().unwrap_or(0);
+                if let Err(e) =
+                    common_monitor_cmd(self.iris, self.sim, self.instance_id, space, c, &mut out)
+                {
+                    outputln!(out, "{}", e);
+                }
             }
         }
         Ok(())
@@ -477,4 +1274,4 @@ impl Arch for Armv8aArch {
     type BreakpointKind = usize;
 }
 
-pub use crate::gdb::t32::GdbOverPipe;
+pub use crate::gdb::t32::{GdbOverPipe, GdbOverTcp};
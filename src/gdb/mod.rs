@@ -1,2 +1,533 @@
+use std::collections::BTreeMap;
+use std::io::Error as IOError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use gdbstub::outputln;
+use gdbstub::target::ext::base::multithread::ThreadStopReason;
+use gdbstub::target::ext::base::singlethread::StopReason;
+use gdbstub::target::ext::base::GdbInterruptNoAsync;
+use gdbstub::target::ext::monitor_cmd::ConsoleOutput;
+
+use crate::{
+    console, event, event_stream, instance_registry, memory, resource, simulation_time, step,
+    CallbackFlow, CallbackToken, CorneaError, FastModelIris,
+};
+
 pub mod a64;
+pub mod riscv;
 pub mod t32;
+
+/// How long to wait for an event before checking for an interrupt from gdb
+const INTERRUPT_POLL: Duration = Duration::from_millis(10);
+
+/// Event streams and callbacks that a gdb stub set up, to be removed when
+/// the stub is dropped
+#[derive(Default)]
+struct Subscriptions {
+    streams: Vec<(u32, u64)>,
+    callbacks: Vec<CallbackToken>,
+}
+
+impl Subscriptions {
+    fn remove(&mut self, iris: &mut FastModelIris) {
+        for (inst, stream) in self.streams.drain(..) {
+            let _ = event_stream::destroy(iris, inst, stream);
+        }
+        for token in self.callbacks.drain(..) {
+            iris.unregister_callback(token);
+        }
+    }
+}
+
+/// The optional gdb features that the model has the Iris functions for,
+/// checked once when a stub is created so that gdb is only offered what
+/// works
+#[derive(Debug, Clone, Copy)]
+struct Capabilities {
+    breakpoints: bool,
+    memory_write: bool,
+    reverse: bool,
+}
+
+impl Capabilities {
+    fn of(iris: &mut FastModelIris, inst: u32, sim: u32) -> Self {
+        // Assume the basics when the model can't say, as older ones may not
+        let mut supports = |inst, method, unknown| iris.supports(inst, method).unwrap_or(unknown);
+        Self {
+            breakpoints: supports(inst, "breakpoint_set", true),
+            memory_write: supports(inst, "memory_write", true),
+            reverse: supports(sim, "simulationTime_runReverse", false),
+        }
+    }
+}
+
+/// A trap instruction written over the code at a software breakpoint
+struct Patch {
+    inst: u32,
+    space: u64,
+    original: Vec<u8>,
+    trap: Vec<u8>,
+}
+
+/// Software breakpoints set by patching a trap instruction into memory,
+/// which leaves the model's breakpoint comparators free. The stubs hide the
+/// traps from gdb's memory reads, and keep them over gdb's memory writes.
+///
+/// This relies on the model stopping the simulation when it executes the
+/// trap, as a model set up to halt on a breakpoint instruction does;
+/// otherwise the target takes the exception the hardware would.
+#[derive(Default)]
+struct SoftwareBreakpoints {
+    patches: BTreeMap<u64, Patch>,
+}
+
+impl SoftwareBreakpoints {
+    /// Write `trap` over the code at `addr`, saving what was there
+    fn insert(
+        &mut self,
+        iris: &mut FastModelIris,
+        inst: u32,
+        space: u64,
+        addr: u64,
+        trap: &[u8],
+    ) -> Result<(), CorneaError> {
+        if self.patches.contains_key(&addr) {
+            return Ok(());
+        }
+        let original = memory::read_bytes(iris, inst, space, addr, trap.len() as u64)?;
+        memory::write_bytes(iris, inst, space, addr, trap)?;
+        self.patches.insert(
+            addr,
+            Patch {
+                inst,
+                space,
+                original,
+                trap: trap.to_vec(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Put back the code under the breakpoint at `addr`. Returns false when
+    /// there is no software breakpoint there.
+    fn remove(&mut self, iris: &mut FastModelIris, addr: u64) -> Result<bool, CorneaError> {
+        match self.patches.remove(&addr) {
+            Some(patch) => {
+                memory::write_bytes(iris, patch.inst, patch.space, addr, &patch.original)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Whether there is a software breakpoint at `addr`
+    fn contains(&self, addr: u64) -> bool {
+        self.patches.contains_key(&addr)
+    }
+
+    /// Put back the code under every breakpoint, such as when gdb goes away.
+    /// Returns the address, memory space and trap of each, for
+    /// `insert_all` to patch them in again.
+    fn remove_all(&mut self, iris: &mut FastModelIris) -> Vec<(u64, u64, Vec<u8>)> {
+        let mut removed = Vec::new();
+        for (addr, patch) in std::mem::take(&mut self.patches) {
+            let _ = memory::write_bytes(iris, patch.inst, patch.space, addr, &patch.original);
+            removed.push((addr, patch.space, patch.trap));
+        }
+        removed
+    }
+
+    /// Patch in again what `remove_all` removed, through core `inst`.
+    /// Returns the addresses where that failed.
+    fn insert_all(
+        &mut self,
+        iris: &mut FastModelIris,
+        inst: u32,
+        removed: Vec<(u64, u64, Vec<u8>)>,
+    ) -> Vec<u64> {
+        let mut failed = Vec::new();
+        for (addr, space, trap) in removed {
+            if self.insert(iris, inst, space, addr, &trap).is_err() {
+                failed.push(addr);
+            }
+        }
+        failed
+    }
+
+    /// The patches that overlap `len` bytes from `addr`, with the offset of
+    /// each into those bytes and into the patch
+    fn overlapping(
+        &mut self,
+        addr: u64,
+        len: usize,
+    ) -> impl Iterator<Item = (usize, usize, &mut Patch)> {
+        let end = addr + len as u64;
+        self.patches
+            .range_mut(addr.saturating_sub(8)..end)
+            .filter(move |(at, patch)| **at + patch.trap.len() as u64 > addr)
+            .map(move |(at, patch)| {
+                let skip = addr.saturating_sub(*at) as usize;
+                let offset = at.saturating_sub(addr) as usize;
+                (offset, skip, patch)
+            })
+    }
+
+    /// Replace the traps in `data`, read from `addr`, with the code they
+    /// cover
+    fn hide(&mut self, addr: u64, data: &mut [u8]) {
+        let len = data.len();
+        for (offset, skip, patch) in self.overlapping(addr, len) {
+            for (byte, orig) in data[offset..].iter_mut().zip(&patch.original[skip..]) {
+                *byte = *orig;
+            }
+        }
+    }
+
+    /// The bytes to write for gdb writing `data` to `addr`: the code under a
+    /// breakpoint is saved for when the breakpoint goes, and the trap stays.
+    fn write_through(&mut self, addr: u64, data: &[u8]) -> Vec<u8> {
+        let mut out = data.to_vec();
+        for (offset, skip, patch) in self.overlapping(addr, data.len()) {
+            let saved = patch.original[skip..].iter_mut().zip(&data[offset..]);
+            for (orig, new) in saved {
+                *orig = *new;
+            }
+            for (byte, trap) in out[offset..].iter_mut().zip(&patch.trap[skip..]) {
+                *byte = *trap;
+            }
+        }
+        out
+    }
+}
+
+/// Ask Iris to send an event when the simulation starts or stops, so that
+/// `run_until_stopped` hears about it without polling. Models without the
+/// event still work, polled instead. Returns whether the model has it.
+fn subscribe_time_events(
+    iris: &mut FastModelIris,
+    sim: u32,
+    subs: &mut Subscriptions,
+) -> Result<bool, CorneaError> {
+    let source = match event::source(iris, sim, "IRIS_SIMULATION_TIME_EVENT".to_string()) {
+        Ok(source) => source,
+        Err(CorneaError::Rpc { .. }) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let my_id = iris.instance_id().unwrap();
+    let stream = event_stream::subscribe(iris, Some(sim), my_id, source.id)?;
+    subs.streams.push((sim, stream));
+    subs.callbacks.push(iris.register_callback(
+        "ec_IRIS_SIMULATION_TIME_EVENT".to_string(),
+        Box::new(|_| Ok(CallbackFlow::Keep)),
+    ));
+    Ok(true)
+}
+
+/// Why the simulation stopped running, for a stub to tell gdb
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stopped {
+    /// It stopped by itself, such as at the end of a step or at a breakpoint
+    Itself,
+    /// gdb interrupted it
+    Interrupted,
+    /// The target reset itself, such as when a watchdog fired
+    Reset,
+    /// The simulation is shutting down
+    Shutdown,
+}
+
+/// gdb's number for SIGPWR, "Power fail/restart", which is the closest it
+/// has to a reset
+const SIGPWR: u8 = 32;
+
+impl Stopped {
+    /// What to tell gdb, or None when the target stopped by itself and the
+    /// stub knows better why
+    fn stop_reason<U>(self) -> Option<StopReason<U>> {
+        match self {
+            Stopped::Itself => None,
+            Stopped::Interrupted => Some(StopReason::GdbInterrupt),
+            Stopped::Reset => Some(StopReason::Signal(SIGPWR)),
+            Stopped::Shutdown => Some(StopReason::Exited(0)),
+        }
+    }
+
+    /// `stop_reason` for the multithreaded stubs
+    fn thread_stop_reason<U>(self) -> Option<ThreadStopReason<U>> {
+        match self {
+            Stopped::Itself => None,
+            Stopped::Interrupted => Some(ThreadStopReason::GdbInterrupt),
+            Stopped::Reset => Some(ThreadStopReason::Signal(SIGPWR)),
+            Stopped::Shutdown => Some(ThreadStopReason::Exited(0)),
+        }
+    }
+}
+
+/// What a stub hears about the simulation while it waits for it to stop
+#[derive(Default)]
+struct Lifecycle {
+    /// The reset or shutdown that the simulation went through, if any
+    seen: Arc<Mutex<Option<Stopped>>>,
+    /// Whether the model sends time events, so that a stop need not be
+    /// polled for
+    time_events: bool,
+}
+
+/// Ask Iris for the events that `run_until_stopped` waits on: the
+/// simulation starting and stopping, and resetting or shutting down, so
+/// that a stub waiting for the target to stop hears that it never will.
+/// Models without the events still work, only without the news.
+fn subscribe_lifecycle(
+    iris: &mut FastModelIris,
+    sim: u32,
+    subs: &mut Subscriptions,
+) -> Result<Lifecycle, CorneaError> {
+    let lifecycle = Lifecycle {
+        time_events: subscribe_time_events(iris, sim, subs)?,
+        ..Lifecycle::default()
+    };
+    let my_id = iris.instance_id().unwrap();
+    let events = [
+        ("IRIS_SIM_PHASE_RESET_ENTER", Stopped::Reset),
+        ("IRIS_SHUTDOWN_ENTER", Stopped::Shutdown),
+    ];
+    for (name, stopped) in events {
+        let source = match event::source(iris, sim, name.to_string()) {
+            Ok(source) => source,
+            Err(CorneaError::Rpc { .. }) => continue,
+            Err(e) => return Err(e),
+        };
+        let stream = event_stream::subscribe(iris, Some(sim), my_id, source.id)?;
+        subs.streams.push((sim, stream));
+        let cb_seen = lifecycle.seen.clone();
+        subs.callbacks.push(iris.register_callback(
+            format!("ec_{}", name),
+            Box::new(move |_| {
+                if let Ok(mut seen) = cb_seen.lock() {
+                    *seen = Some(stopped);
+                }
+                Ok(CallbackFlow::Keep)
+            }),
+        ));
+    }
+    Ok(lifecycle)
+}
+
+/// Collect what the target writes to its console on any of `insts`, for
+/// `monitor console` to show in gdb. Stays empty when the model has no
+/// console event source.
+fn subscribe_console(
+    iris: &mut FastModelIris,
+    insts: &[u32],
+    subs: &mut Subscriptions,
+) -> Result<Arc<Mutex<String>>, CorneaError> {
+    let text = Arc::new(Mutex::new(String::new()));
+    let my_id = iris.instance_id().unwrap();
+    let mut name = None;
+    for inst in insts {
+        if let Some(source) = console::source(iris, *inst)? {
+            let stream = event_stream::subscribe(iris, Some(*inst), my_id, source.id)?;
+            subs.streams.push((*inst, stream));
+            name = Some(source.name);
+        }
+    }
+    if let Some(name) = name {
+        let cb_text = text.clone();
+        subs.callbacks.push(iris.register_callback(
+            format!("ec_{}", name),
+            Box::new(move |params| {
+                if let (Some(new), Ok(mut text)) = (console::decode_text(&params), cb_text.lock()) {
+                    text.push_str(&new);
+                }
+                Ok(CallbackFlow::Keep)
+            }),
+        ));
+    }
+    Ok(text)
+}
+
+/// Run the simulation until it stops by itself, until gdb interrupts it,
+/// or until it resets or shuts down, and say which. The simulation is
+/// stopped in every case.
+fn run_until_stopped(
+    iris: &mut FastModelIris,
+    sim: u32,
+    lifecycle: &Lifecycle,
+    interrupt: &mut GdbInterruptNoAsync<'_>,
+) -> Result<Stopped, CorneaError> {
+    simulation_time::run(iris, sim)?;
+    wait_until_stopped(iris, sim, lifecycle, interrupt)
+}
+
+/// Run the simulation backwards, as `run_until_stopped` runs it forwards
+fn reverse_until_stopped(
+    iris: &mut FastModelIris,
+    sim: u32,
+    lifecycle: &Lifecycle,
+    interrupt: &mut GdbInterruptNoAsync<'_>,
+) -> Result<Stopped, CorneaError> {
+    simulation_time::run_reverse(iris, sim)?;
+    wait_until_stopped(iris, sim, lifecycle, interrupt)
+}
+
+fn wait_until_stopped(
+    iris: &mut FastModelIris,
+    sim: u32,
+    lifecycle: &Lifecycle,
+    interrupt: &mut GdbInterruptNoAsync<'_>,
+) -> Result<Stopped, CorneaError> {
+    // A reset from before this run, such as `monitor reset`, is no news
+    let take = || lifecycle.seen.lock().ok().and_then(|mut seen| seen.take());
+    take();
+    let mut idle = 0;
+    loop {
+        if interrupt.pending() {
+            simulation_time::stop(iris, sim)?;
+            return Ok(Stopped::Interrupted);
+        }
+        // Check after any event, and now and then in case a time event went
+        // missing. Without time events, check on every tick.
+        if iris.wait_for_event(INTERRUPT_POLL)? {
+            idle = 0;
+        } else {
+            idle += 1;
+        }
+        if let Some(stopped) = take() {
+            log_warn!(
+                "The target {}",
+                if stopped == Stopped::Reset {
+                    "reset"
+                } else {
+                    "shut down"
+                }
+            );
+            // A simulation on its way out may not stop, nor need to
+            let _ = simulation_time::stop(iris, sim);
+            return Ok(stopped);
+        }
+        let check = idle == 0 || idle % 50 == 0 || !lifecycle.time_events;
+        if check && !simulation_time::get(iris, sim)?.running {
+            return Ok(Stopped::Itself);
+        }
+    }
+}
+
+/// The monitor commands that every stub understands, run against core
+/// `inst` with memory accesses going to `space`:
+///
+/// * `reset` resets the platform; the stubs handle it themselves, with
+///   `reset_and_resolve`, to set up again what the reset undid
+/// * `memread <addr> <len>` prints a hex dump, both numbers in hex
+/// * `reg <name>` prints one register
+/// * `step <n>` steps `n` instructions and prints the PC
+fn common_monitor_cmd(
+    iris: &mut FastModelIris,
+    sim: u32,
+    inst: u32,
+    space: u64,
+    cmd: &str,
+    out: &mut ConsoleOutput<'_>,
+) -> Result<(), CorneaError> {
+    let mut words = cmd.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("memread"), Some(addr), Some(len)) => {
+            let (addr, len) = match (parse_hex(addr), parse_hex(len)) {
+                (Some(addr), Some(len)) => (addr, len),
+                _ => {
+                    outputln!(out, "Usage: memread <addr> <len>, in hex");
+                    return Ok(());
+                }
+            };
+            let buf = memory::read_bytes(iris, inst, space, addr, len)?;
+            let big_endian = memory::is_big_endian(iris, inst, space)?;
+            outputln!(
+                out,
+                "{}",
+                memory::hex_dump(addr, &buf, 1, big_endian, false).trim_end()
+            );
+        }
+        (Some("reg"), Some(name), None) => match read_register(iris, inst, name)? {
+            Some(value) => outputln!(out, "{} = {}", name, value),
+            None => outputln!(out, "No register {}", name),
+        },
+        (Some("step"), Some(count), None) => {
+            let count = match count.parse() {
+                Ok(count) => count,
+                Err(_) => {
+                    outputln!(out, "Usage: step <n>");
+                    return Ok(());
+                }
+            };
+            step::setup(iris, inst, count, step::Unit::Instruction)?;
+            simulation_time::run(iris, sim)?;
+            while simulation_time::get(iris, sim)?.running {
+                iris.wait_for_event(INTERRUPT_POLL)?;
+            }
+            if let Some(pc) = read_register(iris, inst, "PC")? {
+                outputln!(out, "PC = {}", pc);
+            }
+        }
+        _ => {
+            outputln!(out, "Monitor command {} not supported", cmd);
+        }
+    }
+    Ok(())
+}
+
+/// Reset the simulation engine `sim` for `monitor reset`, and update `sim`
+/// and `inst` to the ids that the engine and core `inst` have afterwards,
+/// which the model may have handed out afresh.
+fn reset_and_resolve(
+    iris: &mut FastModelIris,
+    sim: &mut u32,
+    inst: &mut u32,
+) -> Result<(), CorneaError> {
+    let sim_name = instance_registry::get_instance_by_id(iris, *sim)?.name;
+    let inst_name = instance_registry::get_instance_by_id(iris, *inst)?.name;
+    iris.reset_and_resync(*sim)?;
+    *sim = instance_registry::get_instance_by_name(iris, sim_name)?.id;
+    *inst = instance_registry::get_instance_by_name(iris, inst_name)?.id;
+    Ok(())
+}
+
+/// The error for breakpoints at `addrs` that could not be set again after
+/// `monitor reset`, which gdb still thinks are there
+fn lost_breakpoints(addrs: &[u64]) -> CorneaError {
+    let addrs: Vec<String> = addrs.iter().map(|addr| format!("{:#x}", addr)).collect();
+    IOError::other(format!(
+        "Breakpoints at {} could not be set again after the reset",
+        addrs.join(", ")
+    ))
+    .into()
+}
+
+/// A number in hex, with or without a leading 0x
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text.trim_start_matches("0x"), 16).ok()
+}
+
+/// Read the register of `inst` named `name`, ignoring case, formatted in hex
+fn read_register(
+    iris: &mut FastModelIris,
+    inst: u32,
+    name: &str,
+) -> Result<Option<String>, CorneaError> {
+    let res = match resource::cached_list(iris, inst)?
+        .into_iter()
+        .find(|r| r.name.eq_ignore_ascii_case(name))
+    {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+    let words = resource::read_named(iris, inst, &[&res.name])?
+        .remove(&res.name)
+        .unwrap_or_default();
+    // Words come least significant first
+    let mut words = words.iter().rev();
+    let mut text = format!("{:#x}", words.next().copied().unwrap_or(0));
+    for word in words {
+        text.push_str(&format!("{:016x}", word));
+    }
+    Ok(Some(text))
+}
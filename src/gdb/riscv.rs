@@ -0,0 +1,604 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::{Entry, HashMap};
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+
+use gdbstub::arch::{Arch, RegId, Registers};
+use gdbstub::target::ext::base::singlethread::{
+    SingleThreadOps, SingleThreadRangeStepping, SingleThreadRangeSteppingOps,
+    SingleThreadReverseCont, SingleThreadReverseContOps, SingleThreadReverseStep,
+    SingleThreadReverseStepOps, StopReason,
+};
+use gdbstub::target::ext::base::{BaseOps, GdbInterrupt, ResumeAction};
+use gdbstub::target::ext::breakpoints::{
+    Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, SwBreakpoint, SwBreakpointOps,
+};
+use gdbstub::target::ext::monitor_cmd::{ConsoleOutput, MonitorCmd, MonitorCmdOps};
+use gdbstub::target::ext::target_description_xml_override::{
+    TargetDescriptionXmlOverride, TargetDescriptionXmlOverrideOps,
+};
+use gdbstub::target::{Target, TargetResult};
+use gdbstub::{output, outputln};
+
+use crate::gdb::{
+    common_monitor_cmd, lost_breakpoints, reset_and_resolve, reverse_until_stopped,
+    run_until_stopped, subscribe_console, subscribe_lifecycle, Capabilities, Lifecycle,
+    SoftwareBreakpoints, Subscriptions,
+};
+use crate::{
+    breakpoint, instance_registry, memory, resource, step, CorneaError, FastModelIris, RwMode,
+};
+
+pub struct IrisGdbStub<'i> {
+    pub iris: &'i mut FastModelIris,
+    pub instance_id: u32,
+    sim: u32,
+    // What the target wrote to its console since `monitor console`
+    console: Arc<Mutex<String>>,
+    // Whether the simulation reset or shut down while gdb waited for it
+    lifecycle: Lifecycle,
+    breakpoints: HashMap<u64, u64>,
+    // gdb's software breakpoints, when they are patched into memory
+    patches: SoftwareBreakpoints,
+    patch_breakpoints: bool,
+    caps: Capabilities,
+    subs: Subscriptions,
+}
+
+impl Drop for IrisGdbStub<'_> {
+    fn drop(&mut self) {
+        self.patches.remove_all(self.iris);
+        self.subs.remove(self.iris);
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GuestState {
+    pub x: [u64; 32],
+    pub pc: u64,
+}
+
+impl<'i> IrisGdbStub<'i> {
+    /// A stub for core `instance_id`, running the simulation with the
+    /// model's usual simulation engine
+    pub fn from_instance(
+        iris: &'i mut FastModelIris,
+        instance_id: u32,
+    ) -> Result<Self, CorneaError> {
+        let sim = instance_registry::simulation_engine(iris, None)?;
+        Self::from_instance_and_engine(iris, instance_id, sim.id)
+    }
+
+    /// Like `from_instance`, running the simulation with engine `sim`, such
+    /// as one that `instance_registry::simulation_engine` found by name
+    pub fn from_instance_and_engine(
+        iris: &'i mut FastModelIris,
+        instance_id: u32,
+        sim: u32,
+    ) -> Result<Self, CorneaError> {
+        let caps = Capabilities::of(iris, instance_id, sim);
+        let mut stub = Self {
+            iris,
+            instance_id,
+            breakpoints: HashMap::new(),
+            sim,
+            console: Arc::default(),
+            lifecycle: Lifecycle::default(),
+            patches: SoftwareBreakpoints::default(),
+            patch_breakpoints: false,
+            caps,
+            subs: Subscriptions::default(),
+        };
+        stub.subscribe()?;
+        Ok(stub)
+    }
+
+    /// Set up the event streams and callbacks that the stub waits on
+    fn subscribe(&mut self) -> Result<(), CorneaError> {
+        self.lifecycle = subscribe_lifecycle(self.iris, self.sim, &mut self.subs)?;
+        self.console = subscribe_console(self.iris, &[self.instance_id], &mut self.subs)?;
+        Ok(())
+    }
+
+    /// Reset the platform for `monitor reset`, then set up again what the
+    /// reset undid or may have renumbered: the core, the event streams on
+    /// it, the model's breakpoints and the software breakpoints patched
+    /// into the code.
+    fn reset(&mut self) -> Result<(), CorneaError> {
+        let patches = self.patches.remove_all(self.iris);
+        let mut breakpoints = Vec::new();
+        for (addr, id) in std::mem::take(&mut self.breakpoints) {
+            let _ = breakpoint::delete(self.iris, self.instance_id, id);
+            breakpoints.push(addr);
+        }
+        self.subs.remove(self.iris);
+        let unread = self
+            .console
+            .lock()
+            .map(|mut text| std::mem::take(&mut *text))
+            .unwrap_or_default();
+
+        reset_and_resolve(self.iris, &mut self.sim, &mut self.instance_id)?;
+        self.subscribe()?;
+        if let Ok(mut text) = self.console.lock() {
+            text.insert_str(0, &unread);
+        }
+
+        let mut lost = self
+            .patches
+            .insert_all(self.iris, self.instance_id, patches);
+        for addr in breakpoints {
+            if !matches!(self.add_hw_breakpoint(addr, 0), Ok(true)) {
+                lost.push(addr);
+            }
+        }
+        if lost.is_empty() {
+            Ok(())
+        } else {
+            Err(lost_breakpoints(&lost))
+        }
+    }
+
+    /// Have gdb's software breakpoints patch a trap instruction into memory
+    /// rather than use the model's breakpoints, which are often few. See
+    /// `SoftwareBreakpoints` for what the model needs to do.
+    pub fn set_patch_breakpoints(&mut self, patch: bool) {
+        self.patch_breakpoints = patch && self.caps.memory_write;
+    }
+
+    /// The address of the instruction the core runs next
+    fn pc(&mut self) -> Result<u64, ()> {
+        let val = resource::read_named(self.iris, self.instance_id, &["PC"]).map_err(|_| ())?;
+        let pc = val.get("PC").and_then(|words| words.first()).ok_or(())?;
+        Ok(*pc)
+    }
+
+    /// The stop reason for a core stopped at `pc` when gdb has a breakpoint
+    /// there: a patched trap is a software breakpoint, and the model's
+    /// breakpoints are hardware ones.
+    fn breakpoint_at(&self, pc: u64) -> Option<StopReason<u64>> {
+        if self.patches.contains(pc) {
+            Some(StopReason::SwBreak)
+        } else if self.breakpoints.contains_key(&pc) {
+            Some(StopReason::HwBreak)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `resources` look like the registers of a RISC-V core
+pub fn is_riscv(resources: &[resource::ResourceInfo]) -> bool {
+    resources
+        .iter()
+        .any(|r| r.name == "X31" || r.name.eq_ignore_ascii_case("t6"))
+}
+
+impl Registers for GuestState {
+    type ProgramCounter = u64;
+    fn pc(&self) -> u64 {
+        self.pc
+    }
+    // The layout of gdb's riscv cpu feature: x0-x31, then pc
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        for reg in self.x.iter().chain(Some(&self.pc)) {
+            for byte in reg.to_le_bytes().iter() {
+                write_byte(Some(*byte));
+            }
+        }
+    }
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() != 33 * 8 {
+            return Err(());
+        }
+        let mut regs = bytes
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()));
+        for reg in self.x.iter_mut().chain(Some(&mut self.pc)) {
+            *reg = regs.next().ok_or(())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Register {
+    X(u8),
+    PC,
+}
+
+impl RegId for Register {
+    fn from_raw_id(id: usize) -> Option<(Self, usize)> {
+        match id {
+            0..=31 => Some((Register::X(id as u8), 8)),
+            32 => Some((Register::PC, 8)),
+            _ => None,
+        }
+    }
+}
+
+/// The ABI names of x0 to x31, which some models use for their resources
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// The register that a resource holds
+fn register_for(name: &str) -> Option<Register> {
+    if name == "PC" {
+        return Some(Register::PC);
+    }
+    if let Some(n) = name.strip_prefix('X').and_then(|n| n.parse::<u8>().ok()) {
+        return if n < 32 { Some(Register::X(n)) } else { None };
+    }
+    let lower = name.to_ascii_lowercase();
+    if lower == "fp" {
+        return Some(Register::X(8));
+    }
+    ABI_NAMES
+        .iter()
+        .position(|abi| *abi == lower)
+        .map(|n| Register::X(n as u8))
+}
+
+impl GuestState {
+    fn get(&self, reg: &Register) -> u64 {
+        match reg {
+            Register::X(n) => self.x[*n as usize],
+            Register::PC => self.pc,
+        }
+    }
+
+    fn set(&mut self, reg: &Register, value: u64) {
+        match reg {
+            Register::X(n) => self.x[*n as usize] = value,
+            Register::PC => self.pc = value,
+        }
+    }
+}
+
+impl<'i> Target for IrisGdbStub<'i> {
+    type Arch = Riscv64Arch;
+    type Error = ();
+    fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
+        BaseOps::SingleThread(self)
+    }
+
+    fn breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        if self.caps.breakpoints {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn target_description_xml_override(
+        &mut self,
+    ) -> Option<TargetDescriptionXmlOverrideOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+/// Describes the registers in the order that `GuestState::gdb_serialize`
+/// writes them, which is also the numbering `Register::from_raw_id` uses.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>riscv:rv64</architecture>
+  <feature name="org.gnu.gdb.riscv.cpu">
+    <reg name="zero" bitsize="64" type="int" regnum="0"/>
+    <reg name="ra" bitsize="64" type="code_ptr"/>
+    <reg name="sp" bitsize="64" type="data_ptr"/>
+    <reg name="gp" bitsize="64" type="data_ptr"/>
+    <reg name="tp" bitsize="64" type="data_ptr"/>
+    <reg name="t0" bitsize="64" type="int"/>
+    <reg name="t1" bitsize="64" type="int"/>
+    <reg name="t2" bitsize="64" type="int"/>
+    <reg name="fp" bitsize="64" type="data_ptr"/>
+    <reg name="s1" bitsize="64" type="int"/>
+    <reg name="a0" bitsize="64" type="int"/>
+    <reg name="a1" bitsize="64" type="int"/>
+    <reg name="a2" bitsize="64" type="int"/>
+    <reg name="a3" bitsize="64" type="int"/>
+    <reg name="a4" bitsize="64" type="int"/>
+    <reg name="a5" bitsize="64" type="int"/>
+    <reg name="a6" bitsize="64" type="int"/>
+    <reg name="a7" bitsize="64" type="int"/>
+    <reg name="s2" bitsize="64" type="int"/>
+    <reg name="s3" bitsize="64" type="int"/>
+    <reg name="s4" bitsize="64" type="int"/>
+    <reg name="s5" bitsize="64" type="int"/>
+    <reg name="s6" bitsize="64" type="int"/>
+    <reg name="s7" bitsize="64" type="int"/>
+    <reg name="s8" bitsize="64" type="int"/>
+    <reg name="s9" bitsize="64" type="int"/>
+    <reg name="s10" bitsize="64" type="int"/>
+    <reg name="s11" bitsize="64" type="int"/>
+    <reg name="t3" bitsize="64" type="int"/>
+    <reg name="t4" bitsize="64" type="int"/>
+    <reg name="t5" bitsize="64" type="int"/>
+    <reg name="t6" bitsize="64" type="int"/>
+    <reg name="pc" bitsize="64" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+impl TargetDescriptionXmlOverride for IrisGdbStub<'_> {
+    fn target_description_xml(&self) -> &str {
+        TARGET_XML
+    }
+}
+
+impl SingleThreadOps for IrisGdbStub<'_> {
+    fn support_resume_range_step(&mut self) -> Option<SingleThreadRangeSteppingOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_reverse_step(&mut self) -> Option<SingleThreadReverseStepOps<'_, Self>> {
+        if self.caps.reverse {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn support_reverse_cont(&mut self) -> Option<SingleThreadReverseContOps<'_, Self>> {
+        if self.caps.reverse {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn read_registers(&mut self, regs: &mut GuestState) -> TargetResult<(), Self> {
+        let names: Vec<_> = resource::cached_list(self.iris, self.instance_id)
+            .map_err(|_| ())?
+            .into_iter()
+            .filter(|res| register_for(&res.name).is_some())
+            .map(|res| res.name)
+            .collect();
+        let val = resource::read_named(self.iris, self.instance_id, &names).map_err(|_| ())?;
+        for (name, words) in val {
+            if let (Some(reg), Some(word)) = (register_for(&name), words.first()) {
+                regs.set(&reg, *word);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_addrs(&mut self, start_addr: u64, data: &mut [u8]) -> TargetResult<(), Self> {
+        let mem = memory::read_bytes(
+            self.iris,
+            self.instance_id,
+            0,
+            start_addr,
+            data.len() as u64,
+        )
+        .map_err(|_| ())?;
+        data.copy_from_slice(&mem);
+        self.patches.hide(start_addr, data);
+        Ok(())
+    }
+
+    fn write_addrs(&mut self, start_addr: u64, data: &[u8]) -> TargetResult<(), Self> {
+        let data = self.patches.write_through(start_addr, data);
+        memory::write_bytes(self.iris, self.instance_id, 0, start_addr, &data).map_err(|_| ())?;
+        Ok(())
+    }
+    fn write_registers(&mut self, regs: &GuestState) -> TargetResult<(), Self> {
+        // Only write what gdb changed, as some registers have side effects
+        let mut current = GuestState::default();
+        self.read_registers(&mut current)?;
+        let mut ids = Vec::new();
+        let mut data = Vec::new();
+        for res in resource::cached_list(self.iris, self.instance_id).map_err(|_| ())? {
+            let reg = match register_for(&res.name) {
+                Some(reg) => reg,
+                None => continue,
+            };
+            if res.rw_mode != Some(RwMode::Read) && current.get(&reg) != regs.get(&reg) {
+                ids.push(res.id);
+                data.push(regs.get(&reg));
+            }
+        }
+        if !ids.is_empty() {
+            let res = resource::write(self.iris, self.instance_id, ids, data).map_err(|_| ())?;
+            if res.error.is_some() {
+                return Err(().into());
+            }
+        }
+        Ok(())
+    }
+
+    fn resume(
+        &mut self,
+        act: ResumeAction,
+        intr: gdbstub::target::ext::base::GdbInterrupt<'_>,
+    ) -> Result<StopReason<u64>, ()> {
+        let mut interrupt = intr.no_async();
+        if act == ResumeAction::Step {
+            step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction).map_err(|_| ())?
+        }
+        if act == ResumeAction::Step || act == ResumeAction::Continue {
+            let stopped = run_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt);
+            if let Some(reason) = stopped.map_err(|_| ())?.stop_reason() {
+                return Ok(reason);
+            }
+            let pc = self.pc()?;
+            let at_breakpoint = self.breakpoint_at(pc);
+            if act == ResumeAction::Step {
+                return Ok(at_breakpoint.unwrap_or(StopReason::DoneStep));
+            } else {
+                return Ok(at_breakpoint.unwrap_or(StopReason::HwBreak));
+            }
+        }
+        Err(())
+    }
+}
+
+impl SingleThreadRangeStepping for IrisGdbStub<'_> {
+    /// Step until the PC leaves `start..end`, without asking gdb after
+    /// every instruction. Stops at a breakpoint in the range too, which gdb
+    /// wants to hear about.
+    fn resume_range_step(
+        &mut self,
+        start: u64,
+        end: u64,
+        intr: GdbInterrupt<'_>,
+    ) -> Result<StopReason<u64>, ()> {
+        let mut interrupt = intr.no_async();
+        loop {
+            step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction).map_err(|_| ())?;
+            let stopped = run_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt);
+            if let Some(reason) = stopped.map_err(|_| ())?.stop_reason() {
+                return Ok(reason);
+            }
+            let pc = self.pc()?;
+            if let Some(reason) = self.breakpoint_at(pc) {
+                return Ok(reason);
+            }
+            if !(start..end).contains(&pc) {
+                return Ok(StopReason::DoneStep);
+            }
+        }
+    }
+}
+
+impl SingleThreadReverseStep for IrisGdbStub<'_> {
+    fn reverse_step(&mut self, intr: GdbInterrupt<'_>) -> Result<StopReason<u64>, ()> {
+        let mut interrupt = intr.no_async();
+        step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction).map_err(|_| ())?;
+        let stopped = reverse_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt);
+        if let Some(reason) = stopped.map_err(|_| ())?.stop_reason() {
+            return Ok(reason);
+        }
+        let pc = self.pc()?;
+        let at_breakpoint = self.breakpoint_at(pc);
+        Ok(at_breakpoint.unwrap_or(StopReason::DoneStep))
+    }
+}
+
+impl SingleThreadReverseCont for IrisGdbStub<'_> {
+    fn reverse_cont(&mut self, intr: GdbInterrupt<'_>) -> Result<StopReason<u64>, ()> {
+        let mut interrupt = intr.no_async();
+        let stopped = reverse_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt);
+        if let Some(reason) = stopped.map_err(|_| ())?.stop_reason() {
+            return Ok(reason);
+        }
+        Ok(StopReason::HwBreak)
+    }
+}
+
+impl<'i> Breakpoints for IrisGdbStub<'i> {
+    fn hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+/// EBREAK, and C.EBREAK for gdb's 2-byte kind of breakpoint
+const TRAP: [u8; 4] = [0x73, 0x00, 0x10, 0x00];
+const C_TRAP: [u8; 2] = [0x02, 0x90];
+
+impl<'i> SwBreakpoint for IrisGdbStub<'i> {
+    fn add_sw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        k: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        if !self.patch_breakpoints {
+            return self.add_hw_breakpoint(addr, k);
+        }
+        let trap: &[u8] = if k == 2 { &C_TRAP } else { &TRAP };
+        Ok(self
+            .patches
+            .insert(self.iris, self.instance_id, 0, addr, trap)
+            .is_ok())
+    }
+
+    fn remove_sw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        k: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        match self.patches.remove(self.iris, addr) {
+            Ok(true) => Ok(true),
+            Ok(false) => self.remove_hw_breakpoint(addr, k),
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+impl<'i> HwBreakpoint for IrisGdbStub<'i> {
+    fn add_hw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        _: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        if self.breakpoints.contains_key(&addr) {
+            return Ok(true);
+        }
+        if let Ok(id) = breakpoint::code(self.iris, self.instance_id, addr, None, 0, false) {
+            self.breakpoints.insert(addr, id);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+    fn remove_hw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        _: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        if let Entry::Occupied(ent) = self.breakpoints.entry(addr) {
+            if let Ok(()) = breakpoint::delete(self.iris, self.instance_id, *ent.get()) {
+                let _ = ent.remove_entry();
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        } else {
+            Ok(true)
+        }
+    }
+}
+
+impl<'i> MonitorCmd for IrisGdbStub<'i> {
+    fn handle_monitor_cmd(&mut self, cmd: &[u8], mut out: ConsoleOutput<'_>) -> Result<(), ()> {
+        match String::from_utf8_lossy(cmd).borrow() {
+            "console" => {
+                let text = std::mem::take(&mut *self.console.lock().map_err(|_| ())?);
+                output!(out, "{}", text);
+            }
+            c if c.trim() == "reset" => {
+                if let Err(e) = self.reset() {
+                    outputln!(out, "{}", e);
+                }
+            }
+            c => {
+                if let Err(e) =
+                    common_monitor_cmd(self.iris, self.sim, self.instance_id, 0, c, &mut out)
+                {
+                    outputln!(out, "{}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub enum Riscv64Arch {}
+impl Arch for Riscv64Arch {
+    type Usize = u64;
+    type Registers = GuestState;
+    type RegId = Register;
+    type BreakpointKind = usize;
+}
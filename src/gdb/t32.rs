@@ -1,80 +1,232 @@
 use std::borrow::Borrow;
 use std::collections::hash_map::{Entry, HashMap};
 use std::convert::TryInto;
-use std::io::{Error as IOError, Read, Stdin, Stdout, Write};
+use std::io::{Error as IOError, ErrorKind, Read, Stdin, Stdout, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::mpsc::{channel, Receiver};
-use std::thread::spawn;
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
 
 use gdbstub::arch::{Arch, RegId, Registers};
-use gdbstub::target::ext::base::singlethread::{SingleThreadOps, StopReason};
-use gdbstub::target::ext::base::{BaseOps, ResumeAction};
+use gdbstub::target::ext::base::singlethread::{
+    SingleThreadOps, SingleThreadRangeStepping, SingleThreadRangeSteppingOps,
+    SingleThreadReverseCont, SingleThreadReverseContOps, SingleThreadReverseStep,
+    SingleThreadReverseStepOps, StopReason,
+};
+use gdbstub::target::ext::base::{BaseOps, GdbInterrupt, ResumeAction};
 #[allow(unused)]
 use gdbstub::target::ext::breakpoints::{
     Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, SwBreakpoint, SwBreakpointOps,
 };
 use gdbstub::target::ext::monitor_cmd::{ConsoleOutput, MonitorCmd, MonitorCmdOps};
+use gdbstub::target::ext::target_description_xml_override::{
+    TargetDescriptionXmlOverride, TargetDescriptionXmlOverrideOps,
+};
 use gdbstub::target::{Target, TargetResult};
-use gdbstub::{outputln, Connection};
+use gdbstub::{output, outputln, Connection};
 
+use crate::gdb::{
+    common_monitor_cmd, lost_breakpoints, reset_and_resolve, reverse_until_stopped,
+    run_until_stopped, subscribe_console, subscribe_lifecycle, Capabilities, Lifecycle,
+    SoftwareBreakpoints, Subscriptions,
+};
 use crate::{
-    breakpoint, instance_registry, memory, resource, simulation, simulation_time, step,
-    FastModelIris,
+    breakpoint, instance_registry, memory, resource, step, CorneaError, FastModelIris, RwMode,
 };
 
 pub struct IrisGdbStub<'i> {
     pub iris: &'i mut FastModelIris,
     pub instance_id: u32,
     sim: u32,
+    // What the target wrote to its console since `monitor console`
+    console: Arc<Mutex<String>>,
+    // Whether the simulation reset or shut down while gdb waited for it
+    lifecycle: Lifecycle,
     breakpoints: HashMap<u32, u64>,
+    big_endian: bool,
+    // The byte order `set_big_endian` asked for, which outlasts a reset
+    forced_endian: Option<bool>,
+    // gdb's software breakpoints, when they are patched into memory
+    patches: SoftwareBreakpoints,
+    patch_breakpoints: bool,
+    caps: Capabilities,
+    subs: Subscriptions,
+}
+
+impl Drop for IrisGdbStub<'_> {
+    fn drop(&mut self) {
+        self.patches.remove_all(self.iris);
+        self.subs.remove(self.iris);
+    }
 }
 
 #[derive(Debug, Default, Clone, PartialEq)]
 pub struct GuestState {
-    pub regs: [u32; 26],
+    pub regs: [u32; 32],
+    /// Whether gdb sees the registers in big-endian byte order
+    pub big_endian: bool,
+}
+
+impl GuestState {
+    /// The registers with the bytes of each reversed and the byte order
+    /// flipped, which serializes to the same bytes as `self`.
+    fn swap_bytes(&self) -> Self {
+        Self {
+            regs: self.regs.map(u32::swap_bytes),
+            big_endian: !self.big_endian,
+        }
+    }
 }
 
 impl<'i> IrisGdbStub<'i> {
-    pub fn from_instance(iris: &'i mut FastModelIris, instance_id: u32) -> std::io::Result<Self> {
-        let sim = instance_registry::get_instance_by_name(
-            iris,
-            "framework.SimulationEngine".to_string(),
-        )?;
-        Ok(Self {
+    /// A stub for core `instance_id`, running the simulation with the
+    /// model's usual simulation engine
+    pub fn from_instance(
+        iris: &'i mut FastModelIris,
+        instance_id: u32,
+    ) -> Result<Self, CorneaError> {
+        let sim = instance_registry::simulation_engine(iris, None)?;
+        Self::from_instance_and_engine(iris, instance_id, sim.id)
+    }
+
+    /// Like `from_instance`, running the simulation with engine `sim`, such
+    /// as one that `instance_registry::simulation_engine` found by name
+    pub fn from_instance_and_engine(
+        iris: &'i mut FastModelIris,
+        instance_id: u32,
+        sim: u32,
+    ) -> Result<Self, CorneaError> {
+        let caps = Capabilities::of(iris, instance_id, sim);
+        let big_endian = memory::is_big_endian(iris, instance_id, 0)?;
+        let mut stub = Self {
             iris,
             instance_id,
             breakpoints: HashMap::new(),
-            sim: sim.id,
-        })
+            sim,
+            console: Arc::default(),
+            lifecycle: Lifecycle::default(),
+            big_endian,
+            forced_endian: None,
+            patches: SoftwareBreakpoints::default(),
+            patch_breakpoints: false,
+            caps,
+            subs: Subscriptions::default(),
+        };
+        stub.subscribe()?;
+        Ok(stub)
+    }
+
+    /// Set up the event streams and callbacks that the stub waits on
+    fn subscribe(&mut self) -> Result<(), CorneaError> {
+        self.lifecycle = subscribe_lifecycle(self.iris, self.sim, &mut self.subs)?;
+        self.console = subscribe_console(self.iris, &[self.instance_id], &mut self.subs)?;
+        Ok(())
+    }
+
+    /// Reset the platform for `monitor reset`, then set up again what the
+    /// reset undid or may have renumbered: the core, the event streams on
+    /// it, the model's breakpoints and the software breakpoints patched
+    /// into the code.
+    fn reset(&mut self) -> Result<(), CorneaError> {
+        let patches = self.patches.remove_all(self.iris);
+        let mut breakpoints = Vec::new();
+        for (addr, id) in std::mem::take(&mut self.breakpoints) {
+            let _ = breakpoint::delete(self.iris, self.instance_id, id);
+            breakpoints.push(addr);
+        }
+        self.subs.remove(self.iris);
+        let unread = self
+            .console
+            .lock()
+            .map(|mut text| std::mem::take(&mut *text))
+            .unwrap_or_default();
+
+        reset_and_resolve(self.iris, &mut self.sim, &mut self.instance_id)?;
+        self.subscribe()?;
+        if let Ok(mut text) = self.console.lock() {
+            text.insert_str(0, &unread);
+        }
+        if self.forced_endian.is_none() {
+            self.big_endian = memory::is_big_endian(self.iris, self.instance_id, 0)?;
+        }
+
+        let mut lost = self
+            .patches
+            .insert_all(self.iris, self.instance_id, patches);
+        for addr in breakpoints {
+            if !matches!(self.add_hw_breakpoint(addr, 0), Ok(true)) {
+                lost.push(u64::from(addr));
+            }
+        }
+        if lost.is_empty() {
+            Ok(())
+        } else {
+            Err(lost_breakpoints(&lost))
+        }
+    }
+
+    /// Present registers to gdb in big-endian byte order, overriding what
+    /// the model reports for the core's memory.
+    pub fn set_big_endian(&mut self, big_endian: bool) {
+        self.big_endian = big_endian;
+        self.forced_endian = Some(big_endian);
+    }
+
+    /// Have gdb's software breakpoints patch a trap instruction into memory
+    /// rather than use the model's breakpoints, which are often few. See
+    /// `SoftwareBreakpoints` for what the model needs to do.
+    pub fn set_patch_breakpoints(&mut self, patch: bool) {
+        self.patch_breakpoints = patch && self.caps.memory_write;
+    }
+
+    /// The address of the instruction the core runs next
+    fn pc(&mut self) -> Result<u32, ()> {
+        let val = resource::read_named(self.iris, self.instance_id, &["R15"]).map_err(|_| ())?;
+        let pc = val.get("R15").and_then(|words| words.first()).ok_or(())?;
+        Ok(*pc as u32 & !THUMB_BIT)
+    }
+
+    /// The stop reason for a core stopped at `pc` when gdb has a breakpoint
+    /// there: a patched trap is a software breakpoint, and the model's
+    /// breakpoints are hardware ones.
+    fn breakpoint_at(&self, pc: u32) -> Option<StopReason<u32>> {
+        if self.patches.contains(u64::from(pc)) {
+            Some(StopReason::SwBreak)
+        } else if self.breakpoints.contains_key(&pc) {
+            Some(StopReason::HwBreak)
+        } else {
+            None
+        }
     }
 }
 
 impl Registers for GuestState {
     type ProgramCounter = u32;
     fn pc(&self) -> u32 {
-        self.regs[15]
+        self.regs[15] & !THUMB_BIT
     }
+    // The layout of the target description: r0-r15, xpsr, then the
+    // system registers. The FPA registers 16 to 24 of gdb's default arm
+    // layout are not described.
     fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
-        for (num, reg) in self.regs.iter().enumerate() {
+        if self.big_endian {
+            return self.swap_bytes().gdb_serialize(write_byte);
+        }
+        for reg in self.regs[..16].iter().chain(&self.regs[25..]) {
             for byte in reg.to_le_bytes().iter() {
                 write_byte(Some(*byte));
             }
-            // Registers above 16 and below 24 are assumed to be 96 bit by gdb.
-            // So we pad them
-            if num >= 16 && num < 24 {
-                for _ in 0..8 {
-                    write_byte(Some(0));
-                }
-            }
         }
     }
     fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
-        if bytes.len() % 4 != 0 {
+        if bytes.len() != 23 * 4 {
             return Err(());
         }
         let mut regs = bytes
             .chunks_exact(4)
             .map(|c| u32::from_le_bytes(c.try_into().unwrap()));
-        for reg in &mut self.regs {
+        let (core, rest) = self.regs.split_at_mut(16);
+        for reg in core.iter_mut().chain(&mut rest[9..]) {
             *reg = regs.next().ok_or(())?;
         }
         Ok(())
@@ -100,6 +252,12 @@ pub enum Register {
     LR,
     PC,
     XPSR,
+    MSP,
+    PSP,
+    PRIMASK,
+    BASEPRI,
+    FAULTMASK,
+    CONTROL,
 }
 
 impl RegId for Register {
@@ -123,9 +281,49 @@ impl RegId for Register {
             14 => LR,
             15 => PC,
             25 => XPSR,
+            26 => MSP,
+            27 => PSP,
+            28 => PRIMASK,
+            29 => BASEPRI,
+            30 => FAULTMASK,
+            31 => CONTROL,
             _ => return None,
         })
-        .map(|r| (r, 0))
+        .map(|r| (r, 4))
+    }
+}
+
+/// Bit 0 of an address, which marks Thumb code in branch targets and symbol
+/// values but is not part of the address of an instruction.
+const THUMB_BIT: u32 = 1;
+
+/// Index into `GuestState::regs` of the register that a resource holds
+fn register_number(name: &str) -> Option<usize> {
+    match name {
+        "R0" => Some(0),
+        "R1" => Some(1),
+        "R2" => Some(2),
+        "R3" => Some(3),
+        "R4" => Some(4),
+        "R5" => Some(5),
+        "R6" => Some(6),
+        "R7" => Some(7),
+        "R8" => Some(8),
+        "R9" => Some(9),
+        "R10" => Some(10),
+        "R11" => Some(11),
+        "R12" => Some(12),
+        "R13" => Some(13),
+        "R14" => Some(14),
+        "R15" => Some(15),
+        "XPSR" => Some(25),
+        "MSP" => Some(26),
+        "PSP" => Some(27),
+        "PRIMASK" => Some(28),
+        "BASEPRI" => Some(29),
+        "FAULTMASK" => Some(30),
+        "CONTROL" => Some(31),
+        _ => None,
     }
 }
 
@@ -136,78 +334,161 @@ impl<'i> Target for IrisGdbStub<'i> {
         BaseOps::SingleThread(self)
     }
 
-    fn breakpoints(&mut self) -> Option<BreakpointsOps<Self>> {
+    fn breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        if self.caps.breakpoints {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
         Some(self)
     }
 
-    fn monitor_cmd(&mut self) -> Option<MonitorCmdOps<Self>> {
+    fn target_description_xml_override(
+        &mut self,
+    ) -> Option<TargetDescriptionXmlOverrideOps<'_, Self>> {
         Some(self)
     }
 }
 
+/// Describes the registers in the order that `GuestState::gdb_serialize`
+/// writes them. xpsr keeps regnum 25 from the layout gdb assumes without a
+/// description, and the system registers follow it.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.m-profile">
+    <reg name="r0" bitsize="32" regnum="0"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="xpsr" bitsize="32" regnum="25"/>
+  </feature>
+  <feature name="org.gnu.gdb.arm.m-system">
+    <reg name="msp" bitsize="32" type="data_ptr" regnum="26"/>
+    <reg name="psp" bitsize="32" type="data_ptr"/>
+    <reg name="primask" bitsize="32"/>
+    <reg name="basepri" bitsize="32"/>
+    <reg name="faultmask" bitsize="32"/>
+    <reg name="control" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+impl TargetDescriptionXmlOverride for IrisGdbStub<'_> {
+    fn target_description_xml(&self) -> &str {
+        TARGET_XML
+    }
+}
+
 impl SingleThreadOps for IrisGdbStub<'_> {
+    fn support_resume_range_step(&mut self) -> Option<SingleThreadRangeSteppingOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_reverse_step(&mut self) -> Option<SingleThreadReverseStepOps<'_, Self>> {
+        if self.caps.reverse {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn support_reverse_cont(&mut self) -> Option<SingleThreadReverseContOps<'_, Self>> {
+        if self.caps.reverse {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
     fn read_registers(&mut self, regs: &mut GuestState) -> TargetResult<(), Self> {
-        for res in
-            resource::get_list(&mut self.iris, self.instance_id, None, None).map_err(|_| ())?
-        {
-            let regnum = match res.name.as_str() {
-                "R0" => 0,
-                "R1" => 1,
-                "R2" => 2,
-                "R3" => 3,
-                "R4" => 4,
-                "R5" => 5,
-                "R6" => 6,
-                "R7" => 7,
-                "R8" => 8,
-                "R9" => 9,
-                "R10" => 10,
-                "R11" => 11,
-                "R12" => 12,
-                "R13" => 13,
-                "R14" => 14,
-                "R15" => 15,
-                "XPSR" => 25,
-                _ => continue,
-            };
-            let val =
-                resource::read(&mut self.iris, self.instance_id, vec![res.id]).map_err(|_| ())?;
-            if !val.data.is_empty() {
-                regs.regs[regnum] = val.data[0] as u32
+        regs.big_endian = self.big_endian;
+        let names: Vec<_> = resource::cached_list(self.iris, self.instance_id)
+            .map_err(|_| ())?
+            .into_iter()
+            .filter(|res| register_number(&res.name).is_some())
+            .map(|res| res.name)
+            .collect();
+        let val = resource::read_named(self.iris, self.instance_id, &names).map_err(|_| ())?;
+        for (name, words) in val {
+            if let (Some(regnum), Some(word)) = (register_number(&name), words.first()) {
+                regs.regs[regnum] = *word as u32;
+                if regnum == 15 {
+                    regs.regs[regnum] &= !THUMB_BIT;
+                }
             }
         }
         Ok(())
     }
 
     fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<(), Self> {
-        let mem = memory::read(
-            &mut self.iris,
+        let mem = memory::read_bytes(
+            self.iris,
             self.instance_id,
             0,
             start_addr as u64,
-            1,
             data.len() as u64,
         )
         .map_err(|_| ())?;
-        for (offset, byte) in mem
-            .data
-            .into_iter()
-            .map(|u| u.to_le_bytes())
-            .flatten()
-            .enumerate()
-        {
-            if data.len() > offset {
-                data[offset] = byte;
-            }
-        }
+        data.copy_from_slice(&mem);
+        self.patches.hide(start_addr as u64, data);
         Ok(())
     }
 
-    fn write_addrs(&mut self, _: u32, _: &[u8]) -> TargetResult<(), Self> {
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+        let data = self.patches.write_through(start_addr as u64, data);
+        memory::write_bytes(self.iris, self.instance_id, 0, start_addr as u64, &data)
+            .map_err(|_| ())?;
         Ok(())
     }
-    fn write_registers(&mut self, _: &GuestState) -> TargetResult<(), Self> {
-        // We don't support writing
+    fn write_registers(&mut self, regs: &GuestState) -> TargetResult<(), Self> {
+        // gdbstub reads gdb's registers into a little-endian state
+        let regs = if self.big_endian && !regs.big_endian {
+            &regs.swap_bytes()
+        } else {
+            regs
+        };
+        // Only write what gdb changed, as some registers have side effects
+        let mut current = GuestState::default();
+        self.read_registers(&mut current)?;
+        let mut ids = Vec::new();
+        let mut data = Vec::new();
+        for res in resource::cached_list(self.iris, self.instance_id).map_err(|_| ())? {
+            let regnum = match register_number(&res.name) {
+                Some(regnum) => regnum,
+                None => continue,
+            };
+            let mut value = regs.regs[regnum];
+            if regnum == 15 {
+                value &= !THUMB_BIT;
+            }
+            if res.rw_mode != Some(RwMode::Read) && current.regs[regnum] != value {
+                ids.push(res.id);
+                data.push(value as u64);
+            }
+        }
+        if !ids.is_empty() {
+            let res = resource::write(self.iris, self.instance_id, ids, data).map_err(|_| ())?;
+            if res.error.is_some() {
+                return Err(().into());
+            }
+        }
         Ok(())
     }
 
@@ -221,42 +502,102 @@ impl SingleThreadOps for IrisGdbStub<'_> {
             step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction).map_err(|_| ())?
         }
         if act == ResumeAction::Step || act == ResumeAction::Continue {
-            simulation_time::run(self.iris, self.sim).map_err(|_| ())?;
-            while simulation_time::get(self.iris, self.sim)
-                .map_err(|_| ())?
-                .running
-            {
-                if interrupt.pending() {
-                    simulation_time::stop(self.iris, self.sim).map_err(|_| ())?;
-                    return Ok(StopReason::GdbInterrupt);
-                }
+            let stopped = run_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt);
+            if let Some(reason) = stopped.map_err(|_| ())?.stop_reason() {
+                return Ok(reason);
             }
+            let pc = self.pc()?;
+            let at_breakpoint = self.breakpoint_at(pc);
             if act == ResumeAction::Step {
-                return Ok(StopReason::DoneStep);
+                return Ok(at_breakpoint.unwrap_or(StopReason::DoneStep));
             } else {
-                return Ok(StopReason::HwBreak);
+                return Ok(at_breakpoint.unwrap_or(StopReason::HwBreak));
             }
         }
         Err(())
     }
 }
 
+impl SingleThreadRangeStepping for IrisGdbStub<'_> {
+    /// Step until the PC leaves `start..end`, without asking gdb after
+    /// every instruction. Stops at a breakpoint in the range too, which gdb
+    /// wants to hear about.
+    fn resume_range_step(
+        &mut self,
+        start: u32,
+        end: u32,
+        intr: GdbInterrupt<'_>,
+    ) -> Result<StopReason<u32>, ()> {
+        let mut interrupt = intr.no_async();
+        loop {
+            step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction).map_err(|_| ())?;
+            let stopped = run_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt);
+            if let Some(reason) = stopped.map_err(|_| ())?.stop_reason() {
+                return Ok(reason);
+            }
+            let pc = self.pc()?;
+            if let Some(reason) = self.breakpoint_at(pc) {
+                return Ok(reason);
+            }
+            if !(start..end).contains(&pc) {
+                return Ok(StopReason::DoneStep);
+            }
+        }
+    }
+}
+
+impl SingleThreadReverseStep for IrisGdbStub<'_> {
+    fn reverse_step(&mut self, intr: GdbInterrupt<'_>) -> Result<StopReason<u32>, ()> {
+        let mut interrupt = intr.no_async();
+        step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction).map_err(|_| ())?;
+        let stopped = reverse_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt);
+        if let Some(reason) = stopped.map_err(|_| ())?.stop_reason() {
+            return Ok(reason);
+        }
+        let pc = self.pc()?;
+        let at_breakpoint = self.breakpoint_at(pc);
+        Ok(at_breakpoint.unwrap_or(StopReason::DoneStep))
+    }
+}
+
+impl SingleThreadReverseCont for IrisGdbStub<'_> {
+    fn reverse_cont(&mut self, intr: GdbInterrupt<'_>) -> Result<StopReason<u32>, ()> {
+        let mut interrupt = intr.no_async();
+        let stopped = reverse_until_stopped(self.iris, self.sim, &self.lifecycle, &mut interrupt);
+        if let Some(reason) = stopped.map_err(|_| ())?.stop_reason() {
+            return Ok(reason);
+        }
+        Ok(StopReason::HwBreak)
+    }
+}
+
 impl<'i> Breakpoints for IrisGdbStub<'i> {
-    fn hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
+    fn hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
         Some(self)
     }
 
-    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<Self>> {
+    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
         Some(self)
     }
 }
+
+/// BKPT #0
+const TRAP: [u8; 2] = [0x00, 0xbe];
+
 impl<'i> SwBreakpoint for IrisGdbStub<'i> {
     fn add_sw_breakpoint(
         &mut self,
         addr: <Self::Arch as Arch>::Usize,
         k: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
-        self.add_hw_breakpoint(addr, k)
+        if !self.patch_breakpoints {
+            return self.add_hw_breakpoint(addr, k);
+        }
+        let addr = addr & !THUMB_BIT;
+        Ok(self
+            .patches
+            .insert(self.iris, self.instance_id, 0, addr as u64, &TRAP)
+            .is_ok())
     }
 
     fn remove_sw_breakpoint(
@@ -264,7 +605,12 @@ impl<'i> SwBreakpoint for IrisGdbStub<'i> {
         addr: <Self::Arch as Arch>::Usize,
         k: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
-        self.remove_hw_breakpoint(addr, k)
+        let addr = addr & !THUMB_BIT;
+        match self.patches.remove(self.iris, addr as u64) {
+            Ok(true) => Ok(true),
+            Ok(false) => self.remove_hw_breakpoint(addr, k),
+            Err(_) => Ok(false),
+        }
     }
 }
 
@@ -274,6 +620,7 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
         addr: <Self::Arch as Arch>::Usize,
         _: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
+        let addr = addr & !THUMB_BIT;
         if self.breakpoints.contains_key(&addr) {
             return Ok(true);
         }
@@ -289,6 +636,7 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
         addr: <Self::Arch as Arch>::Usize,
         _: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
+        let addr = addr & !THUMB_BIT;
         if let Entry::Occupied(ent) = self.breakpoints.entry(addr) {
             if let Ok(()) = breakpoint::delete(self.iris, self.instance_id, *ent.get()) {
                 let _ = ent.remove_entry();
@@ -305,12 +653,21 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
 impl<'i> MonitorCmd for IrisGdbStub<'i> {
     fn handle_monitor_cmd(&mut self, cmd: &[u8], mut out: ConsoleOutput<'_>) -> Result<(), ()> {
         match String::from_utf8_lossy(cmd).borrow() {
-            "reset" => {
-                simulation::reset(self.iris, self.sim, false).map_err(|_| ())?;
-                simulation::wait(self.iris, self.sim).map_err(|_| ())?;
+            "console" => {
+                let text = std::mem::take(&mut *self.console.lock().map_err(|_| ())?);
+                output!(out, "{}", text);
+            }
+            c if c.trim() == "reset" => {
+                if let Err(e) = self.reset() {
+                    outputln!(out, "{}", e);
+                }
             }
             c => {
-                outputln!(out, "Monitor command {} not supported", c);
+                if let Err(e) =
+                    common_monitor_cmd(self.iris, self.sim, self.instance_id, 0, c, &mut out)
+                {
+                    outputln!(out, "{}", e);
+                }
             }
         }
         Ok(())
@@ -325,26 +682,53 @@ impl Arch for Armv7mArch {
     type BreakpointKind = usize;
 }
 
+/// A gdb connection over stdin and stdout, as for `target remote | cornea
+/// gdb-proxy`. A thread reads stdin, so that `peek` does not block.
 pub struct GdbOverPipe {
     rx: Receiver<Result<u8, IOError>>,
     write: Stdout,
+    reader: Option<JoinHandle<()>>,
 }
 
-impl<'a> GdbOverPipe {
+impl GdbOverPipe {
     pub fn new(read: Stdin, write: Stdout) -> Self {
         let (tx, rx) = channel();
-        spawn(move || {
+        let reader = spawn(move || {
             let mut byte = [0u8];
             let mut read = read;
             loop {
-                match read.read(&mut byte) {
+                // Stop at the end of input, on an error, and once nobody is
+                // listening any more
+                let (res, last) = match read.read(&mut byte) {
                     Ok(0) => break,
-                    Ok(_) => tx.send(Ok(byte[0])).unwrap(),
-                    Err(error) => tx.send(Err(error)).unwrap(),
+                    Ok(_) => (Ok(byte[0]), false),
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    Err(error) => (Err(error), true),
+                };
+                if tx.send(res).is_err() || last {
+                    break;
                 }
             }
         });
-        Self { rx, write }
+        Self {
+            rx,
+            write,
+            reader: Some(reader),
+        }
+    }
+}
+
+impl Drop for GdbOverPipe {
+    /// Wait for the reader thread, which finishes when gdb closes the pipe,
+    /// as it does after it detaches or disconnects, or when it next reads
+    /// anything.
+    fn drop(&mut self) {
+        // Hang up on the reader, so that it stops at the next byte should
+        // gdb send more before closing the pipe
+        self.rx = channel().1;
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
     }
 }
 
@@ -352,7 +736,7 @@ impl Connection for GdbOverPipe {
     type Error = IOError;
     fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
         let outbuf = [byte; 1];
-        self.write.write(&outbuf)?;
+        self.write.write_all(&outbuf)?;
         self.write.flush()?;
         Ok(())
     }
@@ -371,3 +755,55 @@ impl Connection for GdbOverPipe {
         }
     }
 }
+
+/// A gdb connection over TCP, to one client at a time.
+pub struct GdbOverTcp {
+    stream: TcpStream,
+}
+
+impl GdbOverTcp {
+    /// Listen on `addr` and wait for one gdb to connect
+    pub fn listen(addr: SocketAddr) -> Result<Self, IOError> {
+        Self::accept(&TcpListener::bind(addr)?)
+    }
+
+    /// Wait for the next gdb to connect to `listener`, such as after the last
+    /// one disconnected.
+    pub fn accept(listener: &TcpListener) -> Result<Self, IOError> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+}
+
+impl Connection for GdbOverTcp {
+    type Error = IOError;
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        Write::write_all(&mut self.stream, &[byte])
+    }
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        Write::write_all(&mut self.stream, buf)
+    }
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Write::flush(&mut self.stream)
+    }
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        let mut byte = [0u8];
+        match Read::read(&mut self.stream, &mut byte)? {
+            0 => Err(ErrorKind::ConnectionReset.into()),
+            _ => Ok(byte[0]),
+        }
+    }
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        let mut byte = [0u8];
+        self.stream.set_nonblocking(true)?;
+        let res = self.stream.peek(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match res {
+            Ok(0) => Err(ErrorKind::ConnectionReset.into()),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -1,27 +1,276 @@
+// Diagnostics go to the `log` crate when the `log` feature is on, so that the
+// application decides what to do with them, and to stderr otherwise.
+#[cfg(feature = "log")]
+macro_rules! log_error {
+    ($($arg:tt)*) => { log::error!($($arg)*) };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_error {
+    ($($arg:tt)*) => { eprintln!("Error: {}", format_args!($($arg)*)) };
+}
+
+#[cfg(feature = "log")]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { log::warn!($($arg)*) };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { eprintln!("Warn: {}", format_args!($($arg)*)) };
+}
+
+#[cfg(feature = "log")]
+macro_rules! log_trace {
+    ($($arg:tt)*) => { log::trace!($($arg)*) };
+}
+
+// Wire traces are too noisy for stderr
+#[cfg(not(feature = "log"))]
+macro_rules! log_trace {
+    ($($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+pub mod error {
+    use std::fmt;
+    use std::io::{Error as IOError, ErrorKind};
+
+    use serde::Deserialize;
+    use serde_json::Value;
+
+    /// The error object of a JSON-RPC error response from Iris.
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    pub struct RpcError {
+        pub code: i64,
+        pub message: String,
+        #[serde(default)]
+        pub data: Value,
+    }
+
+    impl fmt::Display for RpcError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} (error code {})", self.message, self.code)
+        }
+    }
+
+    impl std::error::Error for RpcError {}
+
+    /// Everything that can go wrong while talking to an Iris server.
+    #[derive(Debug)]
+    pub enum CorneaError {
+        /// Reading from or writing to the connection failed.
+        Io(IOError),
+        /// Iris answered a request with a JSON-RPC error.
+        Rpc {
+            code: i64,
+            message: String,
+            data: Value,
+        },
+        /// The Iris server did not complete the connection handshake.
+        Handshake(String),
+        /// The Iris server sent something that we could not understand.
+        Protocol(String),
+    }
+
+    impl fmt::Display for CorneaError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Io(e) => write!(f, "{}", e),
+                Self::Rpc { code, message, .. } => {
+                    write!(f, "{} (error code {})", message, code)
+                }
+                Self::Handshake(msg) => write!(f, "Iris handshake failed: {}", msg),
+                Self::Protocol(msg) => write!(f, "Iris protocol error: {}", msg),
+            }
+        }
+    }
+
+    impl std::error::Error for CorneaError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                Self::Io(e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<IOError> for CorneaError {
+        fn from(e: IOError) -> Self {
+            Self::Io(e)
+        }
+    }
+
+    impl From<serde_json::Error> for CorneaError {
+        fn from(e: serde_json::Error) -> Self {
+            Self::Protocol(e.to_string())
+        }
+    }
+
+    impl From<RpcError> for CorneaError {
+        fn from(
+            RpcError {
+                code,
+                message,
+                data,
+            }: RpcError,
+        ) -> Self {
+            Self::Rpc {
+                code,
+                message,
+                data,
+            }
+        }
+    }
+
+    /// For callers that work in `io::Result`. An RPC error becomes an
+    /// `io::Error` of kind `Other` whose inner error is the `RpcError`, so
+    /// that its code and data can still be reached with `downcast_ref`.
+    impl From<CorneaError> for IOError {
+        fn from(e: CorneaError) -> Self {
+            match e {
+                CorneaError::Io(e) => e,
+                CorneaError::Rpc {
+                    code,
+                    message,
+                    data,
+                } => IOError::other(RpcError {
+                    code,
+                    message,
+                    data,
+                }),
+                e @ CorneaError::Handshake(_) => {
+                    IOError::new(ErrorKind::ConnectionRefused, e.to_string())
+                }
+                e @ CorneaError::Protocol(_) => IOError::new(ErrorKind::InvalidData, e.to_string()),
+            }
+        }
+    }
+}
+
 pub mod iris_client {
     use std::collections::{HashMap, HashSet};
-    use std::ffi::OsStr;
-    use std::io::{BufRead, BufReader, Error as IOError, Write};
+    use std::ffi::{OsStr, OsString};
+    use std::io::{BufRead, BufReader, Error as IOError, ErrorKind, Read, Write};
     use std::marker::PhantomData;
-    use std::net::{SocketAddr, TcpStream};
+    use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
     use std::process::{Child, Command, Stdio};
     use std::str::FromStr;
-    use std::time::Instant;
+    use std::thread::{sleep, spawn, JoinHandle};
+    use std::time::{Duration, Instant};
 
     use bufstream::BufStream;
     use serde::{de::DeserializeOwned, Deserialize, Serialize};
-    use serde_json;
+    use serde_json::{self, Value};
 
+    use crate::error::{CorneaError, RpcError};
     use crate::instance_registry;
 
+    type Callback = Box<dyn FnMut(serde_json::Value) -> Result<CallbackFlow, CorneaError>>;
+
+    /// What to do with a callback after it has handled an event.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CallbackFlow {
+        /// Keep calling this callback for later events.
+        Keep,
+        /// Unregister this callback; it will not be called again.
+        Remove,
+        /// Keep this callback, but return from `FastModelIris::run_event_loop`
+        /// once this event has been handled.
+        Stop,
+    }
+
+    /// Identifies one registered callback, so that it may be removed with
+    /// `FastModelIris::unregister_callback`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct CallbackToken(u64);
+
+    /// A byte stream that an Iris connection may be carried over.
+    ///
+    /// Implemented for `TcpStream` and, on unix, `UnixStream`. Other streams,
+    /// such as a scripted in-memory stream for tests, only need an empty
+    /// `impl Transport for MyStream {}`.
+    pub trait Transport: Read + Write {
+        /// Set how long a read may block before failing. Streams that cannot
+        /// time out return an error of kind `Unsupported`.
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> Result<(), IOError> {
+            Err(ErrorKind::Unsupported.into())
+        }
+
+        /// Make reads fail with `WouldBlock` instead of waiting for data.
+        /// Streams that cannot do this return an error of kind `Unsupported`.
+        fn set_nonblocking(&self, _nonblocking: bool) -> Result<(), IOError> {
+            Err(ErrorKind::Unsupported.into())
+        }
+
+        /// Send small writes at once rather than waiting to fill a packet.
+        /// Streams without such a delay return an error of kind
+        /// `Unsupported`.
+        fn set_nodelay(&self, _nodelay: bool) -> Result<(), IOError> {
+            Err(ErrorKind::Unsupported.into())
+        }
+    }
+
+    impl Transport for TcpStream {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IOError> {
+            TcpStream::set_read_timeout(self, timeout)
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> Result<(), IOError> {
+            TcpStream::set_nonblocking(self, nonblocking)
+        }
+
+        fn set_nodelay(&self, nodelay: bool) -> Result<(), IOError> {
+            TcpStream::set_nodelay(self, nodelay)
+        }
+    }
+
+    #[cfg(unix)]
+    impl Transport for UnixStream {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IOError> {
+            UnixStream::set_read_timeout(self, timeout)
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> Result<(), IOError> {
+            UnixStream::set_nonblocking(self, nonblocking)
+        }
+    }
+
     /// An Iris connection to a fast model.
     pub struct FastModelIris {
         proc: Option<Child>,
-        ipc: BufStream<TcpStream>,
-        pub inst_id: Option<u32>,
+        stdout_drain: Option<JoinHandle<()>>,
+        ipc: BufStream<Box<dyn Transport>>,
+        inst_id: Option<u32>,
+        inst_name: String,
         pub startup_time: Instant,
         current_msg_id: u32,
-        callbacks: HashMap<String, Box<dyn FnMut(serde_json::Value) -> Result<(), IOError>>>,
+        // Callbacks for each event method, in registration order
+        callbacks: HashMap<String, Vec<(CallbackToken, Callback)>>,
+        next_callback: u64,
+        // Bytes received from Iris that are not yet part of a complete
+        // message. A read timeout in the middle of a message keeps the bytes
+        // read so far here for the next read.
+        rx: Vec<u8>,
+        // Read timeout set with `set_timeout`
+        timeout: Option<Duration>,
+        // Ids of the messages sent that have not had a response yet
+        in_flight: HashSet<u64>,
+        // Responses that arrived before anyone waited for them
+        pending: HashMap<u64, Result<serde_json::Value, CorneaError>>,
+        // Memory spaces of each instance, filled by `memory::cached_spaces`
+        pub(crate) space_cache: HashMap<u32, Vec<crate::memory::Space>>,
+        // Resources of each instance, filled by `resource::cached_list`
+        pub(crate) resource_cache: HashMap<u32, Vec<crate::resource::ResourceInfo>>,
+        // Answers of `supports`, by instance and function
+        function_cache: HashMap<(u32, String), bool>,
+        // Set when a callback returns `CallbackFlow::Stop`, until the event
+        // loop sees it
+        stop_requested: bool,
     }
     pub struct RpcReq<'a, S> {
         pub method: &'a str,
@@ -33,15 +282,10 @@ pub mod iris_client {
         jsonrpc: &'a str,
         method: &'a str,
         params: &'a S,
-        id: u64,
-    }
-    #[derive(Deserialize, Debug)]
-    pub struct RpcError {
-        #[allow(dead_code)]
-        code: u64,
-        message: String,
+        // Notifications have no id, and get no response
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
     }
-
     #[derive(Deserialize, Debug)]
     #[serde(untagged)]
     pub enum RpcRes {
@@ -63,17 +307,87 @@ pub mod iris_client {
         },
     }
 
-    #[allow(unused)]
-    #[derive(Deserialize, Debug)]
+    /// Iterator over incoming events, returned by `FastModelIris::events`.
+    /// It ends after the connection closes or an error other than a timeout.
+    pub struct Events<'a> {
+        iris: &'a mut FastModelIris,
+        done: bool,
+    }
+
+    impl Iterator for Events<'_> {
+        type Item = Result<(String, serde_json::Value), CorneaError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.done {
+                return None;
+            }
+            let res = loop {
+                let payload = match self.iris.read_frame() {
+                    Ok(Some(payload)) => payload,
+                    Ok(None) => {
+                        self.done = true;
+                        return None;
+                    }
+                    Err(e) => break Err(e),
+                };
+                match self.iris.route(&payload) {
+                    Ok(Some(event)) => break Ok(event),
+                    Ok(None) => (),
+                    Err(e) => break Err(e),
+                }
+            };
+            if let Err(CorneaError::Io(e)) = &res {
+                self.done = e.kind() != ErrorKind::TimedOut;
+            } else {
+                self.done = res.is_err();
+            }
+            Some(res)
+        }
+    }
+
+    /// Whether a resource may be read or written, or which accesses a data
+    /// breakpoint triggers on.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RwMode {
+        #[serde(rename = "r")]
+        Read,
+        #[serde(rename = "w")]
+        Write,
+        #[serde(rename = "rw")]
+        ReadWrite,
+    }
+
+    impl FromStr for RwMode {
+        type Err = String;
+        fn from_str(s: &str) -> Result<Self, String> {
+            match s {
+                "r" => Ok(Self::Read),
+                "w" => Ok(Self::Write),
+                "rw" => Ok(Self::ReadWrite),
+                _ => Err(format!("{} is not r, w or rw", s)),
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct AttributeInfo {
-        description: Option<String>,
-        optional: Option<bool>,
+        pub description: Option<String>,
+        pub optional: Option<bool>,
         #[serde(rename = "type")]
-        typ: String,
+        pub typ: String,
+    }
+
+    #[derive(Hash, Eq, PartialEq)]
+    pub struct MessageHandle<Out>(pub(crate) u64, pub(crate) PhantomData<fn() -> Out>);
+
+    // Written out by hand so that handles are Copy whatever the request type.
+    impl<Out> Clone for MessageHandle<Out> {
+        fn clone(&self) -> Self {
+            *self
+        }
     }
 
-    #[derive(Clone, Copy, Hash, Eq, PartialEq)]
-    pub struct MessageHandle<Out>(u64, PhantomData<Out>);
+    impl<Out> Copy for MessageHandle<Out> {}
 
     #[doc(hidden)]
     fn port_from_stdout<B: BufRead>(out: &mut B) -> Result<Option<u16>, IOError> {
@@ -97,93 +411,452 @@ pub mod iris_client {
         type Out = Void;
     }
 
-    impl FastModelIris {
-        /// Construct a Fast Model from command line arguments
-        pub fn from_args<I, S>(args: I) -> Result<Self, IOError>
+    /// Parameters that were not given a type, as for `execute_raw`
+    impl IrisOut for Value {
+        type Out = Value;
+    }
+
+    /// A serialization format for Iris messages, offered in the handshake
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Format {
+        /// JSON-RPC 2.0 messages framed with `IrisJson:<len>:`
+        IrisJson,
+    }
+
+    impl Format {
+        /// The name of this format in the `Supported-Formats` header
+        pub fn name(self) -> &'static str {
+            match self {
+                Format::IrisJson => "IrisJson",
+            }
+        }
+    }
+
+    /// Settings for a new `FastModelIris`, for when the `from_*`
+    /// constructors are not enough. Set either a `port` to connect to a
+    /// running model or a `command` to start one, then call `connect`.
+    ///
+    /// ```no_run
+    /// use cornea::FastModelIrisBuilder;
+    /// use std::time::Duration;
+    ///
+    /// let fvp = FastModelIrisBuilder::new()
+    ///     .port(7100)
+    ///     .timeout(Some(Duration::from_secs(5)))
+    ///     .instance_name("my-tool")
+    ///     .connect()?;
+    /// # Ok::<(), cornea::CorneaError>(())
+    /// ```
+    pub struct FastModelIrisBuilder {
+        host: String,
+        port: Option<u16>,
+        command: Option<Vec<OsString>>,
+        output: Option<Box<dyn Write + Send>>,
+        timeout: Option<Duration>,
+        nodelay: bool,
+        instance_name: String,
+        format: Format,
+    }
+
+    impl Default for FastModelIrisBuilder {
+        fn default() -> Self {
+            Self {
+                host: "127.0.0.1".to_string(),
+                port: None,
+                command: None,
+                output: None,
+                timeout: None,
+                nodelay: true,
+                instance_name: "cornea".to_string(),
+                format: Format::IrisJson,
+            }
+        }
+    }
+
+    impl FastModelIrisBuilder {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Connect to a model already listening on this TCP port
+        pub fn port(mut self, portnum: u16) -> Self {
+            self.port = Some(portnum);
+            self
+        }
+
+        /// The host name or IP address of the machine to find the `port`
+        /// on, this machine by default
+        pub fn host(mut self, host: &str) -> Self {
+            self.host = host.to_string();
+            self
+        }
+
+        /// Start a model with this command line, program first. `-I -p` are
+        /// added so that the model starts its Iris server and reports the
+        /// port.
+        pub fn command<I, S>(mut self, args: I) -> Self
         where
             I: IntoIterator<Item = S>,
             S: AsRef<OsStr>,
         {
-            let mut args = args.into_iter();
-            let _ = args.next();
-            match args.next() {
-                Some(comm) => {
-                    let mut proc = Command::new(comm)
-                        .args(args)
-                        .arg("-I")
-                        .arg("-p")
-                        .stdout(Stdio::piped())
-                        .spawn()?;
-                    let portnum = {
-                        let stdout = proc.stdout.as_mut().unwrap();
-                        let mut out = BufReader::new(stdout);
-                        port_from_stdout(&mut out)?.unwrap()
-                    };
-                    Self::from_port(Some(proc), portnum)
+            let args = args.into_iter().map(|a| a.as_ref().to_os_string());
+            self.command = Some(args.collect());
+            self
+        }
+
+        /// Where to forward the stdout of a model started with `command`.
+        /// It is discarded by default.
+        pub fn output<W: Write + Send + 'static>(mut self, output: W) -> Self {
+            self.output = Some(Box::new(output));
+            self
+        }
+
+        /// See `FastModelIris::set_timeout`. The timeout also covers the
+        /// handshake and registration in `connect`.
+        pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+            self.timeout = timeout;
+            self
+        }
+
+        /// See `FastModelIris::set_nodelay`. On by default.
+        pub fn nodelay(mut self, nodelay: bool) -> Self {
+            self.nodelay = nodelay;
+            self
+        }
+
+        /// The name to register with Iris, `"cornea"` by default. Iris adds
+        /// a suffix when another client already has it.
+        pub fn instance_name(mut self, name: &str) -> Self {
+            self.instance_name = name.to_string();
+            self
+        }
+
+        /// The serialization format to ask the Iris server for
+        pub fn prefer_format(mut self, format: Format) -> Self {
+            self.format = format;
+            self
+        }
+
+        /// Open the connection, negotiate the format and register with Iris
+        pub fn connect(self) -> Result<FastModelIris, CorneaError> {
+            let name = self.instance_name.clone();
+            let format = self.format;
+            let mut fvp = self.open()?;
+            fvp.register_with(&name, format)?;
+            Ok(fvp)
+        }
+
+        /// Open the connection without registering
+        #[doc(hidden)]
+        fn open(self) -> Result<FastModelIris, CorneaError> {
+            let mut fvp = match (self.port, self.command) {
+                (Some(portnum), None) => {
+                    let ipc = TcpStream::connect((self.host.as_str(), portnum))?;
+                    FastModelIris::new(None, Box::new(ipc))
+                }
+                (None, Some(command)) => {
+                    let output = self.output.unwrap_or_else(|| Box::new(std::io::sink()));
+                    spawn_model(&command, output)?
+                }
+                (Some(_), Some(_)) => {
+                    return Err(IOError::new(
+                        ErrorKind::InvalidInput,
+                        "Set either a port or a command, not both",
+                    )
+                    .into())
+                }
+                (None, None) => {
+                    return Err(IOError::new(
+                        ErrorKind::InvalidInput,
+                        "No fvp command line or port specified",
+                    )
+                    .into())
                 }
-                None => {
-                    panic!("No fvp command line specified");
+            };
+            if self.timeout.is_some() {
+                fvp.set_timeout(self.timeout)?;
+            }
+            fvp.set_nodelay(self.nodelay)?;
+            Ok(fvp)
+        }
+    }
+
+    /// Start the model in `command` and connect to the port that it reports.
+    ///
+    /// The model's stdout has to be read continuously, or else the model
+    /// blocks once the pipe fills up. A background thread copies it into
+    /// `output` until the model exits.
+    #[doc(hidden)]
+    fn spawn_model(
+        command: &[OsString],
+        mut output: Box<dyn Write + Send>,
+    ) -> Result<FastModelIris, CorneaError> {
+        let (comm, args) = command.split_first().ok_or_else(|| {
+            IOError::new(ErrorKind::InvalidInput, "No fvp command line specified")
+        })?;
+        let mut proc = Command::new(comm)
+            .args(args)
+            .arg("-I")
+            .arg("-p")
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut out = BufReader::new(proc.stdout.take().unwrap());
+        let portnum = port_from_stdout(&mut out)?.ok_or_else(|| {
+            CorneaError::Handshake("The model exited before reporting its Iris port".to_string())
+        })?;
+        let drain = spawn(move || {
+            let _ = std::io::copy(&mut out, &mut output);
+        });
+        let ipc = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], portnum)))?;
+        let mut fvp = FastModelIris::new(Some(proc), Box::new(ipc));
+        fvp.stdout_drain = Some(drain);
+        Ok(fvp)
+    }
+
+    impl FastModelIris {
+        /// Construct a Fast Model from command line arguments. The model's
+        /// stdout is discarded once it reports its Iris port.
+        pub fn from_args<I, S>(args: I) -> Result<Self, CorneaError>
+        where
+            I: IntoIterator<Item = S>,
+            S: AsRef<OsStr>,
+        {
+            Self::from_args_with_output(args, std::io::sink())
+        }
+
+        /// Construct a Fast Model from command line arguments, forwarding
+        /// everything the model prints to stdout after reporting its Iris
+        /// port into `output`.
+        ///
+        /// The model's stdout has to be read continuously, or else the model
+        /// blocks once the pipe fills up. A background thread does this until
+        /// the model exits.
+        pub fn from_args_with_output<I, S, W>(args: I, output: W) -> Result<Self, CorneaError>
+        where
+            I: IntoIterator<Item = S>,
+            S: AsRef<OsStr>,
+            W: Write + Send + 'static,
+        {
+            FastModelIrisBuilder::new()
+                .command(args.into_iter().skip(1))
+                .output(output)
+                .open()
+        }
+
+        /// Connect to the Iris server of a model listening on a local TCP port
+        pub fn from_port(proc: Option<Child>, portnum: u16) -> Result<Self, CorneaError> {
+            let mut fvp = FastModelIrisBuilder::new().port(portnum).open()?;
+            fvp.proc = proc;
+            Ok(fvp)
+        }
+
+        /// Connect to the Iris server of a model listening at `addr`, such
+        /// as `"10.0.0.5:7100"` for a model on another machine
+        pub fn from_addr<A: ToSocketAddrs>(
+            proc: Option<Child>,
+            addr: A,
+        ) -> Result<Self, CorneaError> {
+            let ipc = TcpStream::connect(addr)?;
+            Ok(Self::new(proc, Box::new(ipc)))
+        }
+
+        /// Connect to the Iris server of a model listening on a local TCP port,
+        /// trying up to `attempts` times with `delay` between each try. This
+        /// covers a model that was just started and is not listening yet.
+        /// Returns the error from the last try when none succeed.
+        pub fn from_port_retry(
+            proc: Option<Child>,
+            portnum: u16,
+            attempts: u32,
+            delay: Duration,
+        ) -> Result<Self, CorneaError> {
+            Self::from_addr_retry(proc, ("127.0.0.1", portnum), attempts, delay)
+        }
+
+        /// Like `from_port_retry`, for a model listening at `addr`
+        pub fn from_addr_retry<A: ToSocketAddrs>(
+            proc: Option<Child>,
+            addr: A,
+            attempts: u32,
+            delay: Duration,
+        ) -> Result<Self, CorneaError> {
+            let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+            let mut tries = 0;
+            loop {
+                tries += 1;
+                match TcpStream::connect(&addrs[..]) {
+                    Ok(ipc) => return Ok(Self::new(proc, Box::new(ipc))),
+                    Err(e) if tries >= attempts => return Err(e.into()),
+                    Err(_) => sleep(delay),
                 }
             }
         }
 
-        pub fn from_port(proc: Option<Child>, portnum: u16) -> Result<Self, IOError> {
-            let startup_time = Instant::now();
-            let ipc = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], portnum)))?;
-            let ipc = BufStream::new(ipc);
-            Ok(Self {
+        /// Talk to an Iris server over an already connected stream
+        pub fn from_stream<S: Transport + 'static>(stream: S) -> Result<Self, CorneaError> {
+            Ok(Self::new(None, Box::new(stream)))
+        }
+
+        /// Replay a session recorded with `recording::Recorder` from the
+        /// file at `path`. Making the same calls as the recorded session
+        /// gets the same responses, with no model needed.
+        pub fn from_recording<P: AsRef<Path>>(path: P) -> Result<Self, CorneaError> {
+            let file = BufReader::new(std::fs::File::open(path)?);
+            Self::from_stream(crate::recording::Replay::new(file)?)
+        }
+
+        #[doc(hidden)]
+        fn new(proc: Option<Child>, stream: Box<dyn Transport>) -> Self {
+            // Iris messages are small requests that each wait for a response,
+            // so waiting to fill a packet only adds latency. Streams without
+            // such a delay are fine as they are.
+            let _ = stream.set_nodelay(true);
+            Self {
                 proc,
-                ipc,
+                stdout_drain: None,
+                ipc: BufStream::new(stream),
                 inst_id: None,
-                startup_time,
+                inst_name: String::new(),
+                startup_time: Instant::now(),
                 current_msg_id: 0,
                 callbacks: HashMap::new(),
-            })
+                next_callback: 0,
+                rx: Vec::new(),
+                timeout: None,
+                in_flight: HashSet::new(),
+                pending: HashMap::new(),
+                space_cache: HashMap::new(),
+                resource_cache: HashMap::new(),
+                function_cache: HashMap::new(),
+                stop_requested: false,
+            }
+        }
+
+        /// Set the maximum time that `wait` and `wait_for_many` block for
+        /// the next message from the model. When the timeout expires, they
+        /// return an error of kind `TimedOut`. `None` blocks forever, which
+        /// is the default. Fails with `Unsupported` on streams that cannot time
+        /// out.
+        pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), CorneaError> {
+            self.ipc.get_ref().set_read_timeout(timeout)?;
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        /// Whether small messages are sent at once, which is the default, or
+        /// may be delayed to combine them into fewer packets. Fails with
+        /// `Unsupported` on streams, such as unix sockets, without the delay.
+        pub fn set_nodelay(&mut self, nodelay: bool) -> Result<(), CorneaError> {
+            self.ipc.get_ref().set_nodelay(nodelay)?;
+            Ok(())
+        }
+
+        /// Forget everything cached about the model, such as memory spaces
+        /// and resources. Call this after `simulation::reset`, which may
+        /// renumber them.
+        pub fn invalidate_caches(&mut self) {
+            self.space_cache.clear();
+            self.resource_cache.clear();
+            self.function_cache.clear();
+        }
+
+        /// Reset the simulation engine `sim_id`, wait for the model to be
+        /// instantiated again and forget everything cached about it, which
+        /// the reset may have changed.
+        pub fn reset_and_resync(&mut self, sim_id: u32) -> Result<(), CorneaError> {
+            crate::simulation::reset(self, sim_id, false)?;
+            self.invalidate_caches();
+            crate::simulation::wait(self, sim_id)
+        }
+
+        /// Whether instance `inst` provides the Iris function `method`, such
+        /// as `memory_write` or `checkpoint_save`, according to
+        /// `instance_getFunctionInfo`. Answers are cached until
+        /// `invalidate_caches`.
+        pub fn supports(&mut self, inst: u32, method: &str) -> Result<bool, CorneaError> {
+            let key = (inst, method.to_string());
+            if let Some(&supported) = self.function_cache.get(&key) {
+                return Ok(supported);
+            }
+            let functions = instance_registry::get_function_info(self, inst, method.to_string())?;
+            let supported = functions.contains_key(method);
+            self.function_cache.insert(key, supported);
+            Ok(supported)
         }
 
         /// Register this struct as a component within Iris within the attached fast
         /// model. This will negotiate protocl, version and serialization formats.
-        pub fn register(&mut self) -> Result<u32, IOError> {
+        pub fn register(&mut self) -> Result<u32, CorneaError> {
+            self.register_as("cornea")
+        }
+
+        /// Like `register`, but register under `name` instead of `"cornea"`.
+        /// Iris adds a suffix when another client already has the name; see
+        /// `instance_name` for the name it gave.
+        pub fn register_as(&mut self, name: &str) -> Result<u32, CorneaError> {
+            self.register_with(name, Format::IrisJson)
+        }
+
+        #[doc(hidden)]
+        fn register_with(&mut self, name: &str, format: Format) -> Result<u32, CorneaError> {
             // Send initial Handshake, including supported serialization.
-            self.ipc
-                .write(b"CONNECT / IrisRpc/1.0\r\nSupported-Formats: IrisJson\r\n\r\n")?;
+            self.ipc.write_all(&handshake(format))?;
             self.ipc.flush()?;
-            // Assert that the Iris server supportes the serialization formats that
-            // we can send.
-            match self.read_formats()? {
-                None => {
-                    return Err(IOError::new(
-                        std::io::ErrorKind::Other,
-                        "The Iris server hug up before completing the handshake",
+            // Something other than an Iris server may never answer, so don't
+            // wait for it forever
+            let waiting = self.timeout.is_none()
+                && self
+                    .ipc
+                    .get_ref()
+                    .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+                    .is_ok();
+            let response = self.read_handshake();
+            if waiting {
+                self.ipc.get_ref().set_read_timeout(None)?;
+            }
+            let response = match response {
+                Err(CorneaError::Io(e)) if e.kind() == ErrorKind::TimedOut => {
+                    return Err(CorneaError::Handshake(
+                        "The server did not answer; is it an Iris server?".to_string(),
                     ))
                 }
-                Some(formats) => {
-                    if !formats.contains(&"IrisJson".to_string()) {
-                        return Err(IOError::new(
-                            std::io::ErrorKind::Other,
-                            "The Iris server does not support IrisJson",
-                        ));
-                    }
-                }
-            }
+                response => response?,
+            };
+            check_handshake(response, format)?;
 
             // Register ourselves as an object within Iris
-            let registration =
-                instance_registry::register_instance(self, "cornea".to_string(), true)?;
+            let registration = instance_registry::register_instance(self, name.to_string(), true)?;
             self.inst_id = Some(registration.id);
+            self.inst_name = registration.name;
             Ok(registration.id)
         }
 
+        /// The id Iris gave this connection in `register`, or `None` before
+        /// registering and after unregistering.
+        pub fn instance_id(&self) -> Option<u32> {
+            self.inst_id
+        }
+
+        /// The instance name Iris gave this connection in `register`. Iris
+        /// may have added a suffix to make it unique. Empty when not
+        /// registered.
+        pub fn instance_name(&self) -> &str {
+            &self.inst_name
+        }
+
+        /// Read the handshake response, its status line and headers, up to
+        /// and including the blank line that ends them. `None` when the
+        /// connection closes first. This reads through the same buffer as
+        /// every later message, so any bytes that arrive with the handshake
+        /// are kept for the first `wait`.
         #[doc(hidden)]
-        fn read_formats(&mut self) -> Result<Option<Vec<String>>, IOError> {
-            for line in BufReader::new(&mut self.ipc).lines() {
-                let line = line?;
-                if let Some(formats) = line.strip_prefix("Supported-Formats: ") {
-                    let formats = formats
-                        .split_ascii_whitespace()
-                        .map(|x| x.trim_end_matches(",").to_string());
-                    return Ok(Some(formats.collect()));
+        fn read_handshake(&mut self) -> Result<Option<Vec<String>>, CorneaError> {
+            let mut lines = Vec::new();
+            while let Some(line) = self.read_line()? {
+                if line.is_empty() {
+                    return Ok(Some(lines));
                 }
+                lines.push(line);
             }
             Ok(None)
         }
@@ -194,13 +867,10 @@ pub mod iris_client {
         pub fn send<'a, M: Serialize + 'a, I: Into<RpcReq<'a, M>>>(
             &mut self,
             message: I,
-        ) -> Result<MessageHandle<M>, IOError> {
+        ) -> Result<MessageHandle<M>, CorneaError> {
             let input = vec![message.into()];
             let output = self.send_many(input)?;
-            for v in output.into_iter() {
-                return Ok(v);
-            }
-            unreachable!()
+            Ok(output.into_iter().next().unwrap())
         }
 
         /// Send a batch of messages to Iris within the Fast Model. This returns a
@@ -209,7 +879,7 @@ pub mod iris_client {
         pub fn send_many<'a, Itr, Itm, M>(
             &mut self,
             messages: Itr,
-        ) -> Result<Vec<MessageHandle<M>>, IOError>
+        ) -> Result<Vec<MessageHandle<M>>, CorneaError>
         where
             Itr: IntoIterator<Item = Itm>,
             Itm: Into<RpcReq<'a, M>>,
@@ -218,177 +888,876 @@ pub mod iris_client {
             let mut res = Vec::new();
             for msg in messages.into_iter() {
                 let RpcReq { method, params } = msg.into();
-                let msg = _RpcReq {
-                    method,
-                    params,
-                    id: ((self.inst_id.unwrap_or(0) as u64) << 32) | self.current_msg_id as u64,
-                    jsonrpc: "2.0",
-                };
-                self.current_msg_id += 1;
-                let msg_text = serde_json::to_string(&msg).unwrap();
-                //eprintln!("-> {:?}", msg_text);
-                res.push(MessageHandle(msg.id, PhantomData));
-                write!(self.ipc, "IrisJson:{}:{}\n", msg_text.len(), msg_text)?;
+                let id = next_msg_id(
+                    self.inst_id,
+                    &mut self.current_msg_id,
+                    &self.in_flight,
+                    &self.pending,
+                );
+                self.ipc
+                    .write_all(&encode_frame(method, params, Some(id))?)?;
+                res.push(MessageHandle(id, PhantomData));
+                self.in_flight.insert(id);
             }
             self.ipc.flush()?;
             Ok(res)
         }
 
-        /// Wait for a message with the specified handle. Throws away all other
-        /// messages that are read from the channel.
+        /// Send a notification to Iris within the Fast Model. Notifications
+        /// have no id, so Iris sends no response and there is nothing to wait
+        /// for.
+        pub fn notify<'a, M: Serialize + 'a, I: Into<RpcReq<'a, M>>>(
+            &mut self,
+            message: I,
+        ) -> Result<(), CorneaError> {
+            let RpcReq { method, params } = message.into();
+            self.ipc.write_all(&encode_frame(method, params, None)?)?;
+            self.ipc.flush()?;
+            Ok(())
+        }
+
+        /// Wait for a message with the specified handle. Responses to other
+        /// messages that arrive first are kept for their own `wait`.
         pub fn wait<M: IrisOut>(
             &mut self,
             msg: MessageHandle<M>,
-        ) -> Result<<M as IrisOut>::Out, IOError> {
+        ) -> Result<<M as IrisOut>::Out, CorneaError> {
             let input = vec![msg];
             let output = self.wait_for_many(input)?;
-            for v in output.into_iter() {
-                return Ok(v);
+            output.into_iter().next().ok_or_else(closed_before_response)
+        }
+
+        /// Wait until any one of the messages in the specified handle set has a
+        /// response, and return its handle along with the result. The other
+        /// handles stay valid and may be passed to a later `wait`.
+        pub fn wait_for_any<I, M>(
+            &mut self,
+            msgs: I,
+        ) -> Result<(MessageHandle<M>, <M as IrisOut>::Out), CorneaError>
+        where
+            I: IntoIterator<Item = MessageHandle<M>>,
+            M: IrisOut,
+        {
+            let msgs = msgs.into_iter().collect::<Vec<_>>();
+            if msgs.is_empty() {
+                return Err(
+                    IOError::new(ErrorKind::InvalidInput, "No messages to wait for").into(),
+                );
+            }
+            loop {
+                if let Some(&msg) = msgs.iter().find(|m| self.pending.contains_key(&m.0)) {
+                    let result = self.pending.remove(&msg.0).unwrap();
+                    return Ok((msg, serde_json::from_value(result?)?));
+                }
+                match self.read_frame()? {
+                    Some(payload) => self.dispatch(&payload)?,
+                    None => return Err(closed_before_response()),
+                }
             }
-            Err(IOError::new(
-                std::io::ErrorKind::Other,
-                "Connection closed before response",
-            ))
         }
 
-        /// Wait for all messages within the specified handle set. Throws away all other
-        /// messages that are read from the channel.
-        pub fn wait_for_many<I, M>(&mut self, msgs: I) -> Result<Vec<<M as IrisOut>::Out>, IOError>
+        /// Wait for all messages within the specified handle set, returning the
+        /// results in the same order as the handles. Responses to other
+        /// messages that arrive first are kept for their own `wait`.
+        ///
+        /// When a timeout is configured with `set_timeout` and no message
+        /// arrives in time, this returns an error of kind `TimedOut`. A
+        /// handle given twice, or one that was already waited for, is an
+        /// error of kind `InvalidInput`.
+        pub fn wait_for_many<I, M>(
+            &mut self,
+            msgs: I,
+        ) -> Result<Vec<<M as IrisOut>::Out>, CorneaError>
         where
             I: IntoIterator<Item = MessageHandle<M>>,
             M: IrisOut,
         {
-            let mut msgs = msgs
+            let ids = msgs
                 .into_iter()
                 .map(|MessageHandle(id, ..)| id)
-                .collect::<HashSet<_>>();
-            if msgs.len() < 1 {
-                return Ok(Vec::new());
-            }
-            let mut out = Vec::with_capacity(msgs.len());
-            for line in (&mut self.ipc).lines() {
-                let line = line?;
-                if let Some(without_header) = line.strip_prefix("IrisJson:") {
-                    let mut parts = without_header.splitn(2, ":");
-                    let size = parts.next().map(usize::from_str);
-                    let payload = parts.next();
-                    match (size, payload) {
-                        (Some(size), Some(payload)) => {
-                            let size = size.expect("HERE");
-                            if payload.len() == size {
-                                //eprintln!("<- {:?}",payload);
-                                let res: Result<RpcRes, _> = serde_json::from_str(payload);
-                                match res {
-                                    Ok(RpcRes::Responce { id, result, .. }) => {
-                                        if msgs.contains(&id) {
-                                            msgs.remove(&id);
-                                            out.push(serde_json::from_value(result)?);
-                                            if msgs.is_empty() {
-                                                return Ok(out);
-                                            }
-                                        } else {
-                                            eprintln!(
-                                                "Received unexpected response: {} {:#?}",
-                                                id, result
-                                            );
-                                        }
-                                    }
-                                    Ok(RpcRes::Event { method, params, .. }) => {
-                                        if let Some(cb) = self.callbacks.get_mut(&method) {
-                                            cb(params)?;
-                                        } else {
-                                            eprintln!(
-                                                "Warn: Unhandled callback {} {:#?}",
-                                                method, params
-                                            );
-                                        }
-                                    }
-                                    Ok(RpcRes::Error { error, .. }) => {
-                                        return Err(IOError::new(
-                                            std::io::ErrorKind::Other,
-                                            error.message,
-                                        ))
-                                    }
-                                    Err(_e) => {
-                                        return Err(IOError::new(
-                                            std::io::ErrorKind::Other,
-                                            payload.to_string(),
-                                        ))
-                                    }
-                                }
-                            } else {
-                                eprintln!("Error: ipc length did not match computed length");
-                            }
-                        }
-                        (Some(_), None) => eprintln!("Error: ipc missing payload"),
-                        (None, Some(_)) => {
-                            unreachable!("Somehow got something afte a : but nothing before it")
-                        }
-                        (None, None) => eprintln!("Error: ipc missing length, payload"),
-                    }
-                } else {
-                    eprintln!(
-                        "Error: line from ipc in did not start with IrisJson\n{}",
-                        line
-                    );
+                .collect::<Vec<_>>();
+            let mut seen = HashSet::new();
+            for id in &ids {
+                if !seen.insert(*id) {
+                    return Err(IOError::new(
+                        ErrorKind::InvalidInput,
+                        format!("Message {} waited for twice", id),
+                    )
+                    .into());
+                }
+                if !self.in_flight.contains(id) && !self.pending.contains_key(id) {
+                    return Err(IOError::new(
+                        ErrorKind::InvalidInput,
+                        format!("Message {} was already waited for", id),
+                    )
+                    .into());
                 }
             }
-            Err(IOError::new(
-                std::io::ErrorKind::Other,
-                "Connection closed before response",
-            ))
+            while !ids.iter().all(|id| self.pending.contains_key(id)) {
+                match self.read_frame()? {
+                    Some(payload) => self.dispatch(&payload)?,
+                    None => return Err(closed_before_response()),
+                }
+            }
+            let results = ids
+                .iter()
+                .map(|id| self.pending.remove(id).unwrap())
+                .collect::<Vec<_>>();
+            let mut out = Vec::with_capacity(results.len());
+            for result in results {
+                out.push(serde_json::from_value(result?)?);
+            }
+            Ok(out)
         }
 
-        /// Execute an RPC with Iris within the Fast Model.
-        pub fn execute<'a, M, I>(&mut self, message: I) -> Result<<M as IrisOut>::Out, IOError>
-        where
-            M: Serialize + IrisOut + 'a,
-            I: Into<RpcReq<'a, M>>,
-        {
-            self.send(message).and_then(|r| self.wait(r))
+        /// Handle the messages that have already arrived from Iris, without
+        /// waiting for more. Events are passed to their callbacks and
+        /// responses are kept for a later `wait`. Returns the number of
+        /// messages handled. A message that has only partly arrived stays
+        /// buffered for the next call.
+        ///
+        /// On a transport without non-blocking reads, only the messages that
+        /// are already buffered are handled.
+        pub fn poll_events(&mut self) -> Result<usize, CorneaError> {
+            self.poll_frames(|fvp, payload| fvp.dispatch(payload))
         }
 
-        pub fn wait_for_events(&mut self) -> IOError {
-            let handle: MessageHandle<()> = MessageHandle(0, PhantomData);
-            self.wait(handle).unwrap_err()
+        /// Take the events that have already arrived from Iris, without
+        /// waiting for more, as their method and parameters. Like `events`,
+        /// this does not pass them to callbacks, and responses are kept for
+        /// a later `wait`.
+        ///
+        /// Running the simulation and then calling this collects what it
+        /// sent without registering any callbacks.
+        pub fn drain_events(&mut self) -> Result<Vec<(String, serde_json::Value)>, CorneaError> {
+            let mut events = Vec::new();
+            self.poll_frames(|fvp, payload| {
+                events.extend(fvp.route(payload)?);
+                Ok(())
+            })?;
+            Ok(events)
         }
 
-        /// Execute a Batch of with Iris within the Fast Model.
-        pub fn batch<'a, M, Itr, Itm>(
-            &mut self,
-            messages: Itr,
-        ) -> Result<Vec<<M as IrisOut>::Out>, IOError>
+        /// Pass each message that has already arrived to `handle`, returning
+        /// how many there were
+        #[doc(hidden)]
+        fn poll_frames<F>(&mut self, mut handle: F) -> Result<usize, CorneaError>
         where
-            M: Serialize + IrisOut + 'a,
-            Itr: IntoIterator<Item = Itm>,
-            Itm: Into<RpcReq<'a, M>>,
+            F: FnMut(&mut Self, &[u8]) -> Result<(), CorneaError>,
         {
-            self.send_many(messages).and_then(|r| self.wait_for_many(r))
+            let mut handled = 0;
+            loop {
+                while let Some(payload) = self.parse_frame()? {
+                    handle(self, &payload)?;
+                    handled += 1;
+                }
+                if self.ipc.get_ref().set_nonblocking(true).is_err() {
+                    return Ok(handled);
+                }
+                let filled = self.fill_rx();
+                self.ipc.get_ref().set_nonblocking(false)?;
+                match filled {
+                    Ok(true) => (),
+                    Ok(false) => return Ok(handled),
+                    Err(CorneaError::Io(e)) if e.kind() == ErrorKind::TimedOut => {
+                        return Ok(handled)
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
         }
 
-        #[allow(unused)]
-        pub fn close(mut self) -> Result<(), IOError> {
-            if let Some(mut proc) = self.proc {
+        /// Wait up to `timeout` for one message from Iris and handle it like
+        /// `poll_events` does. Returns false when nothing arrived in time.
+        ///
+        /// On a transport that cannot time out, this does not wait, and only
+        /// handles the messages that are already buffered.
+        pub fn wait_for_event(&mut self, timeout: Duration) -> Result<bool, CorneaError> {
+            if let Some(payload) = self.parse_frame()? {
+                self.dispatch(&payload)?;
+                return Ok(true);
+            }
+            if self.ipc.get_ref().set_read_timeout(Some(timeout)).is_err() {
+                return Ok(self.poll_events()? > 0);
+            }
+            let res = self.read_frame();
+            self.ipc.get_ref().set_read_timeout(self.timeout)?;
+            match res {
+                Ok(Some(payload)) => {
+                    self.dispatch(&payload)?;
+                    Ok(true)
+                }
+                Ok(None) => Err(closed_before_response()),
+                Err(CorneaError::Io(e)) if e.kind() == ErrorKind::TimedOut => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+
+        /// Handle one message from Iris: run the callback for an event, or
+        /// keep a response until it is waited for.
+        #[doc(hidden)]
+        fn dispatch(&mut self, payload: &[u8]) -> Result<(), CorneaError> {
+            let (method, params) = match self.route(payload)? {
+                Some(event) => event,
+                None => return Ok(()),
+            };
+            match self.callbacks.get_mut(&method) {
+                Some(cbs) if !cbs.is_empty() => {
+                    let mut i = 0;
+                    while i < cbs.len() {
+                        match (cbs[i].1)(params.clone())? {
+                            CallbackFlow::Keep => i += 1,
+                            CallbackFlow::Stop => {
+                                self.stop_requested = true;
+                                i += 1;
+                            }
+                            CallbackFlow::Remove => {
+                                let _ = cbs.remove(i);
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    log_warn!("Unhandled callback {} {:#?}", method, params);
+                }
+            }
+            Ok(())
+        }
+
+        /// Keep a response until it is waited for, or return the method and
+        /// parameters of an event.
+        #[doc(hidden)]
+        fn route(
+            &mut self,
+            payload: &[u8],
+        ) -> Result<Option<(String, serde_json::Value)>, CorneaError> {
+            let (id, result) = match decode_frame(payload)? {
+                Incoming::Response(id, result) => (id, result),
+                Incoming::Event(method, params) => return Ok(Some((method, params))),
+            };
+            if self.in_flight.remove(&id) {
+                self.pending.insert(id, result);
+            } else {
+                log_warn!("Received unexpected response: {} {:#?}", id, result);
+            }
+            Ok(None)
+        }
+
+        /// Iterate over the events that Iris sends, as their method and
+        /// parameters, until the connection closes. Events read this way are
+        /// not passed to callbacks. Responses that arrive in between are kept
+        /// for their own `wait`.
+        pub fn events(&mut self) -> Events<'_> {
+            Events {
+                iris: self,
+                done: false,
+            }
+        }
+
+        /// Read more bytes from the Iris connection into `rx`. Returns false
+        /// when the connection is closed.
+        #[doc(hidden)]
+        fn fill_rx(&mut self) -> Result<bool, CorneaError> {
+            let buf = match self.ipc.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(true),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(IOError::new(
+                        ErrorKind::TimedOut,
+                        "Timed out waiting for a message from Iris",
+                    )
+                    .into())
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if buf.is_empty() {
+                return Ok(false);
+            }
+            let len = buf.len();
+            self.rx.extend_from_slice(buf);
+            self.ipc.consume(len);
+            Ok(true)
+        }
+
+        /// Read a single line from the Iris connection, without the line
+        /// terminator. Returns `None` when the connection is closed.
+        #[doc(hidden)]
+        fn read_line(&mut self) -> Result<Option<String>, CorneaError> {
+            loop {
+                if let Some(end) = self.rx.iter().position(|&b| b == b'\n') {
+                    let mut line: Vec<u8> = self.rx.drain(..=end).collect();
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                    return String::from_utf8(line)
+                        .map(Some)
+                        .map_err(|e| CorneaError::Protocol(e.to_string()));
+                }
+                if !self.fill_rx()? {
+                    return Ok(None);
+                }
+            }
+        }
+
+        /// Read the payload of the next `IrisJson:<len>:<payload>` message.
+        /// Returns `None` when the connection is closed.
+        #[doc(hidden)]
+        fn read_frame(&mut self) -> Result<Option<Vec<u8>>, CorneaError> {
+            loop {
+                if let Some(payload) = self.parse_frame()? {
+                    return Ok(Some(payload));
+                }
+                if !self.fill_rx()? {
+                    return Ok(None);
+                }
+            }
+        }
+
+        #[doc(hidden)]
+        fn parse_frame(&mut self) -> Result<Option<Vec<u8>>, CorneaError> {
+            parse_frame(&mut self.rx)
+        }
+
+        /// Execute an RPC with Iris within the Fast Model.
+        pub fn execute<'a, M, I>(&mut self, message: I) -> Result<<M as IrisOut>::Out, CorneaError>
+        where
+            M: Serialize + IrisOut + 'a,
+            I: Into<RpcReq<'a, M>>,
+        {
+            self.send(message).and_then(|r| self.wait(r))
+        }
+
+        /// Call any Iris `method` with `params`, which should be a JSON
+        /// object, and return its result as the model sent it. For the
+        /// functions that no wrapper covers yet.
+        pub fn execute_raw(&mut self, method: &str, params: Value) -> Result<Value, CorneaError> {
+            self.execute(RpcReq {
+                method,
+                params: &params,
+            })
+        }
+
+        /// Pass events to their callbacks as they arrive. Returns `Ok` when
+        /// the model closes the connection or a callback returns
+        /// `CallbackFlow::Stop`, and an error when the connection fails or a
+        /// callback does. Responses that arrive in between are kept for
+        /// their own `wait`.
+        pub fn run_event_loop(&mut self) -> Result<(), CorneaError> {
+            self.stop_requested = false;
+            while let Some(payload) = self.read_frame()? {
+                self.dispatch(&payload)?;
+                if std::mem::take(&mut self.stop_requested) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+
+        /// Execute a Batch of with Iris within the Fast Model.
+        pub fn batch<'a, M, Itr, Itm>(
+            &mut self,
+            messages: Itr,
+        ) -> Result<Vec<<M as IrisOut>::Out>, CorneaError>
+        where
+            M: Serialize + IrisOut + 'a,
+            Itr: IntoIterator<Item = Itm>,
+            Itm: Into<RpcReq<'a, M>>,
+        {
+            self.send_many(messages).and_then(|r| self.wait_for_many(r))
+        }
+
+        /// Flush what is left of the requests sent so far, unregister from Iris
+        /// and stop the model, if we started it. This also happens when the
+        /// connection is dropped, but `close` reports errors from flushing
+        /// and from stopping the model.
+        ///
+        /// Unregistering is best effort: a server that already hung up or
+        /// does not answer within a second is left to notice on its own.
+        pub fn close(mut self) -> Result<(), CorneaError> {
+            self.shutdown()
+        }
+
+        #[doc(hidden)]
+        fn shutdown(&mut self) -> Result<(), CorneaError> {
+            // Requests written without a flush would otherwise be lost with
+            // the connection. Tear down regardless, and report it after.
+            let flushed = self.ipc.flush();
+            if let Some(id) = self.inst_id.take() {
+                // Don't let an unresponsive model hang the close
+                if self.timeout.is_none() {
+                    let _ = self.set_timeout(Some(Duration::from_secs(1)));
+                }
+                let _ = instance_registry::unregister_instance(self, id);
+            }
+            self.inst_name.clear();
+            if let Some(mut proc) = self.proc.take() {
                 proc.kill()?;
                 proc.wait()?;
             }
-            Ok(())
+            if let Some(drain) = self.stdout_drain.take() {
+                // The model is gone, so its stdout is at end of file
+                let _ = drain.join();
+            }
+            Ok(flushed?)
         }
 
+        /// Call `cb` with the parameters of every `method` event. Callbacks
+        /// for the same method are called in the order they were registered.
+        /// A callback that returns `CallbackFlow::Remove` is unregistered.
         pub fn register_callback(
             &mut self,
             method: String,
-            cb: Box<dyn FnMut(serde_json::Value) -> Result<(), IOError>>,
-        ) {
-            self.callbacks.insert(method, cb);
+            cb: Box<dyn FnMut(serde_json::Value) -> Result<CallbackFlow, CorneaError>>,
+        ) -> CallbackToken {
+            let token = CallbackToken(self.next_callback);
+            self.next_callback += 1;
+            self.callbacks.entry(method).or_default().push((token, cb));
+            token
+        }
+
+        /// Remove a callback added with `register_callback`. Returns false if
+        /// it was already removed.
+        pub fn unregister_callback(&mut self, token: CallbackToken) -> bool {
+            for cbs in self.callbacks.values_mut() {
+                if let Some(pos) = cbs.iter().position(|(t, _)| *t == token) {
+                    let _ = cbs.remove(pos);
+                    return true;
+                }
+            }
+            false
+        }
+    }
+
+    /// Does what `close` does, flushing buffered requests first, but ignores
+    /// its errors.
+    impl Drop for FastModelIris {
+        fn drop(&mut self) {
+            let _ = self.shutdown();
+        }
+    }
+
+    /// Remove one complete message from the front of `rx`, if there is one.
+    /// `<len>` counts the bytes of the payload, so the payload is taken by
+    /// length rather than up to the next newline.
+    pub(crate) fn parse_frame(rx: &mut Vec<u8>) -> Result<Option<Vec<u8>>, CorneaError> {
+        const HEADER: &[u8] = b"IrisJson:";
+        let skip = rx.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        rx.drain(..skip);
+        if rx.len() < HEADER.len() {
+            if HEADER.starts_with(rx) {
+                return Ok(None);
+            }
+        } else if rx.starts_with(HEADER) {
+            let rest = &rx[HEADER.len()..];
+            let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+            match rest.get(digits) {
+                None => return Ok(None),
+                Some(b':') if digits > 0 => {
+                    let size = std::str::from_utf8(&rest[..digits])
+                        .ok()
+                        .and_then(|s| usize::from_str(s).ok())
+                        .ok_or_else(|| {
+                            CorneaError::Protocol("IrisJson length out of range".to_string())
+                        })?;
+                    let start = HEADER.len() + digits + 1;
+                    if rx.len() < start + size {
+                        return Ok(None);
+                    }
+                    // Each message ends with a newline. Anything else where
+                    // it should be means `<len>` was wrong, and every
+                    // message after this one would be misread, so give up
+                    // on the connection rather than try to resynchronize.
+                    if let Some(b) = rx.get(start + size) {
+                        if !b.is_ascii_whitespace() {
+                            return Err(CorneaError::Protocol(
+                                "IrisJson length did not match the message".to_string(),
+                            ));
+                        }
+                    }
+                    let payload = rx[start..start + size].to_vec();
+                    rx.drain(..start + size);
+                    return Ok(Some(payload));
+                }
+                Some(_) => {
+                    return Err(CorneaError::Protocol(
+                        "IrisJson message is missing its length".to_string(),
+                    ))
+                }
+            }
+        }
+        // Not a message we understand; report and skip the rest of the line.
+        match rx.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                let line: Vec<u8> = rx.drain(..=end).collect();
+                log_error!(
+                    "line from ipc in did not start with IrisJson\n{}",
+                    String::from_utf8_lossy(&line)
+                );
+                parse_frame(rx)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Encode a request as an `IrisJson:<len>:<payload>` message
+    pub(crate) fn encode_frame<S: Serialize>(
+        method: &str,
+        params: &S,
+        id: Option<u64>,
+    ) -> Result<Vec<u8>, CorneaError> {
+        let msg_text = serde_json::to_string(&_RpcReq {
+            jsonrpc: "2.0",
+            method,
+            params,
+            id,
+        })?;
+        log_trace!("-> {:?}", msg_text);
+        Ok(format!("IrisJson:{}:{}\n", msg_text.len(), msg_text).into_bytes())
+    }
+
+    /// A message from Iris
+    pub(crate) enum Incoming {
+        Event(String, serde_json::Value),
+        Response(u64, Result<serde_json::Value, CorneaError>),
+    }
+
+    pub(crate) fn decode_frame(payload: &[u8]) -> Result<Incoming, CorneaError> {
+        log_trace!("<- {:?}", String::from_utf8_lossy(payload));
+        match serde_json::from_slice(payload) {
+            Ok(RpcRes::Responce { id, result, .. }) => Ok(Incoming::Response(id, Ok(result))),
+            Ok(RpcRes::Error { id, error }) => Ok(Incoming::Response(id, Err(error.into()))),
+            Ok(RpcRes::Event { method, params, .. }) => Ok(Incoming::Event(method, params)),
+            Err(_e) => Err(CorneaError::Protocol(
+                String::from_utf8_lossy(payload).into_owned(),
+            )),
+        }
+    }
+
+    /// The request that opens an Iris connection, offering only `format`
+    pub(crate) fn handshake(format: Format) -> Vec<u8> {
+        format!(
+            "CONNECT / IrisRpc/1.0\r\nSupported-Formats: {}\r\n\r\n",
+            format.name()
+        )
+        .into_bytes()
+    }
+
+    /// The formats listed by a `Supported-Formats` line of the handshake
+    /// response, or `None` for any other line
+    pub(crate) fn parse_formats(line: &str) -> Option<Vec<String>> {
+        let fmts = line.strip_prefix("Supported-Formats: ")?;
+        let fmts = fmts
+            .split_ascii_whitespace()
+            .map(|x| x.trim_end_matches(',').to_string());
+        Some(fmts.collect())
+    }
+
+    /// How long to wait for the handshake response when no timeout is set
+    pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Check the lines of the handshake response, without the blank line
+    /// that ends them: the server has to speak IrisRpc/1.0, accept the
+    /// connection and support `format`. `None` is a connection that closed
+    /// before the end of the response.
+    pub(crate) fn check_handshake(
+        response: Option<Vec<String>>,
+        format: Format,
+    ) -> Result<(), CorneaError> {
+        let err = |msg: String| Err(CorneaError::Handshake(msg));
+        let lines = match response {
+            Some(lines) => lines,
+            None => {
+                return err("The Iris server hung up before completing the handshake".to_string())
+            }
+        };
+        // Such as `IrisRpc/1.0 101 Switching Protocols`
+        let status = lines.first().map(String::as_str).unwrap_or("");
+        let mut parts = status.splitn(3, ' ');
+        let protocol = parts.next().unwrap_or("");
+        let code = parts.next().unwrap_or("");
+        let reason = parts.next().unwrap_or("");
+        match protocol.strip_prefix("IrisRpc/") {
+            Some("1.0") => (),
+            Some(version) => {
+                return err(format!(
+                    "The server speaks IrisRpc/{} rather than IrisRpc/1.0",
+                    version
+                ))
+            }
+            None => return err(format!("Not an Iris server; it answered {:?}", status)),
+        }
+        if !(code == "101" || code.starts_with('2')) {
+            return err(format!(
+                "The Iris server rejected the connection: {} {}",
+                code, reason
+            ));
+        }
+        let formats = lines[1..].iter().find_map(|line| parse_formats(line));
+        match formats {
+            Some(formats) if formats.iter().any(|f| f == format.name()) => Ok(()),
+            _ => err(format!(
+                "The Iris server does not support {}",
+                format.name()
+            )),
+        }
+    }
+
+    /// Pick the id of the next message, skipping any still waiting for a
+    /// response so that a wrapped counter can't alias one of them.
+    pub(crate) fn next_msg_id<V>(
+        inst_id: Option<u32>,
+        current_msg_id: &mut u32,
+        in_flight: &HashSet<u64>,
+        pending: &HashMap<u64, V>,
+    ) -> u64 {
+        loop {
+            let id = ((inst_id.unwrap_or(0) as u64) << 32) | *current_msg_id as u64;
+            *current_msg_id = current_msg_id.wrapping_add(1);
+            if !in_flight.contains(&id) && !pending.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    pub(crate) fn closed_before_response() -> CorneaError {
+        IOError::new(
+            ErrorKind::UnexpectedEof,
+            "Connection closed before response",
+        )
+        .into()
+    }
+
+    #[cfg(test)]
+    pub(crate) mod tests {
+        use super::*;
+        use serde_json::json;
+        use std::cell::RefCell;
+        use std::collections::VecDeque;
+        use std::rc::Rc;
+
+        /// A transport that reads back what a test scripted, and keeps what
+        /// is written for the test to look at. Reads past the script find
+        /// the end of the stream.
+        #[derive(Clone, Default)]
+        pub(crate) struct Script {
+            pub(crate) incoming: Rc<RefCell<VecDeque<u8>>>,
+            pub(crate) sent: Rc<RefCell<Vec<u8>>>,
+        }
+
+        impl Script {
+            pub(crate) fn new(incoming: &[u8]) -> Self {
+                let script = Self::default();
+                script.incoming.borrow_mut().extend(incoming);
+                script
+            }
+        }
+
+        impl Read for Script {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+                let mut incoming = self.incoming.borrow_mut();
+                let len = buf.len().min(incoming.len());
+                for (byte, new) in buf.iter_mut().zip(incoming.drain(..len)) {
+                    *byte = new;
+                }
+                Ok(len)
+            }
+        }
+
+        impl Write for Script {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+                self.sent.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> Result<(), IOError> {
+                Ok(())
+            }
+        }
+
+        impl Transport for Script {}
+
+        /// A framed response to message `id`
+        pub(crate) fn response(id: u64, result: Value) -> Vec<u8> {
+            let text = json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string();
+            format!("IrisJson:{}:{}\n", text.len(), text).into_bytes()
+        }
+
+        fn request(fvp: &mut FastModelIris, method: &str) -> MessageHandle<Value> {
+            fvp.send(RpcReq {
+                method,
+                params: &json!({}),
+            })
+            .unwrap()
+        }
+
+        #[test]
+        fn parse_frame_takes_the_payload_by_length() {
+            let mut rx = b"IrisJson:9:{\"a\":\"\n\"}\nIrisJson:2:{}\n".to_vec();
+            assert_eq!(parse_frame(&mut rx).unwrap().unwrap(), b"{\"a\":\"\n\"}");
+            assert_eq!(parse_frame(&mut rx).unwrap().unwrap(), b"{}");
+            assert_eq!(parse_frame(&mut rx).unwrap(), None);
+        }
+
+        #[test]
+        fn parse_frame_waits_for_a_short_payload() {
+            let mut rx = b"IrisJson:7:{\"a\"".to_vec();
+            assert_eq!(parse_frame(&mut rx).unwrap(), None);
+            assert_eq!(rx, b"IrisJson:7:{\"a\"");
+            rx.extend_from_slice(b":1}\n");
+            assert_eq!(parse_frame(&mut rx).unwrap().unwrap(), b"{\"a\":1}");
+        }
+
+        #[test]
+        fn parse_frame_rejects_a_bad_length() {
+            let mut rx = b"IrisJson:5:{\"a\":1}\n".to_vec();
+            assert!(matches!(
+                parse_frame(&mut rx),
+                Err(CorneaError::Protocol(_))
+            ));
+            let mut rx = b"IrisJson::{}\n".to_vec();
+            assert!(matches!(
+                parse_frame(&mut rx),
+                Err(CorneaError::Protocol(_))
+            ));
+            let mut rx = b"IrisJson:99999999999999999999999:{}\n".to_vec();
+            assert!(matches!(
+                parse_frame(&mut rx),
+                Err(CorneaError::Protocol(_))
+            ));
+        }
+
+        #[test]
+        fn check_handshake_wants_iris_rpc_and_the_format() {
+            let lines = |lines: &[&str]| Some(lines.iter().map(|l| l.to_string()).collect());
+            let ok = [
+                "IrisRpc/1.0 101 Switching Protocols",
+                "Supported-Formats: IrisJson",
+            ];
+            assert!(check_handshake(lines(&ok), Format::IrisJson).is_ok());
+            for bad in [
+                &[
+                    "IrisRpc/2.0 101 Switching Protocols",
+                    "Supported-Formats: IrisJson",
+                ][..],
+                &["IrisRpc/1.0 403 Forbidden", "Supported-Formats: IrisJson"],
+                &[
+                    "IrisRpc/1.0 101 Switching Protocols",
+                    "Supported-Formats: IrisU64Json",
+                ],
+                &["HTTP/1.1 400 Bad Request"],
+            ] {
+                let res = check_handshake(lines(bad), Format::IrisJson);
+                assert!(matches!(res, Err(CorneaError::Handshake(_))), "{:?}", bad);
+            }
+            let res = check_handshake(None, Format::IrisJson);
+            assert!(matches!(res, Err(CorneaError::Handshake(_))));
+        }
+
+        #[test]
+        fn register_keeps_a_response_that_came_with_the_handshake() {
+            let mut incoming =
+                b"IrisRpc/1.0 101 Switching Protocols\r\nSupported-Formats: IrisJson\r\n\r\n"
+                    .to_vec();
+            incoming.extend(response(0, json!({"instId": 7, "instName": "cornea"})));
+            let script = Script::new(&incoming);
+            let mut fvp = FastModelIris::from_stream(script.clone()).unwrap();
+            assert_eq!(fvp.register().unwrap(), 7);
+            assert_eq!(fvp.instance_name(), "cornea");
+            assert!(script.incoming.borrow().is_empty());
+        }
+
+        #[test]
+        fn close_flushes_what_was_written() {
+            let script = Script::default();
+            let mut fvp = FastModelIris::from_stream(script.clone()).unwrap();
+            let frame = encode_frame("last_words", &json!({}), None).unwrap();
+            // As a send loop of the caller's own would, without a flush
+            fvp.ipc.write_all(&frame).unwrap();
+            assert!(script.sent.borrow().is_empty());
+            // `close` is `shutdown` and then a drop, which would flush the
+            // buffer as well but only after a model we started is stopped,
+            // so look in between
+            fvp.shutdown().unwrap();
+            assert_eq!(*script.sent.borrow(), frame);
+            fvp.close().unwrap();
+        }
+
+        #[test]
+        fn next_msg_id_wraps_around_ids_in_use() {
+            let mut current = u32::MAX;
+            let in_flight = std::iter::once(0x5_0000_0000).collect();
+            let pending = HashMap::<u64, ()>::new();
+            assert_eq!(
+                next_msg_id(Some(5), &mut current, &in_flight, &pending),
+                0x5_ffff_ffff
+            );
+            assert_eq!(
+                next_msg_id(Some(5), &mut current, &in_flight, &pending),
+                0x5_0000_0001
+            );
+        }
+
+        #[test]
+        fn responses_out_of_order_go_to_their_own_wait() {
+            let mut incoming = response(1, json!("one"));
+            incoming.extend(response(0, json!("zero")));
+            incoming.extend(response(2, json!("two")));
+            let mut fvp = FastModelIris::from_stream(Script::new(&incoming)).unwrap();
+            let first = request(&mut fvp, "first");
+            let second = request(&mut fvp, "second");
+            let third = request(&mut fvp, "third");
+            let (handle, result) = fvp.wait_for_any(vec![first, third]).unwrap();
+            assert_eq!((handle.0, result), (0, json!("zero")));
+            let results = fvp.wait_for_many(vec![third, second]).unwrap();
+            assert_eq!(results, [json!("two"), json!("one")]);
+        }
+
+        #[test]
+        fn wait_for_many_rejects_repeated_handles() {
+            let incoming = response(0, json!(null));
+            let mut fvp = FastModelIris::from_stream(Script::new(&incoming)).unwrap();
+            let handle = request(&mut fvp, "only");
+            let twice = fvp.wait_for_many(vec![handle, handle]);
+            assert!(
+                matches!(twice, Err(CorneaError::Io(e)) if e.kind() == ErrorKind::InvalidInput)
+            );
+            fvp.wait(handle).unwrap();
+            let again = fvp.wait(handle);
+            assert!(
+                matches!(again, Err(CorneaError::Io(e)) if e.kind() == ErrorKind::InvalidInput)
+            );
         }
     }
 }
 
+/// Define a function that calls an Iris method and waits for its result,
+/// along with a struct for its parameters. Each parameter becomes both an
+/// argument of the function and a field of the struct, so the struct may also
+/// be passed to `FastModelIris::send_many` or `FastModelIris::batch`. Serde
+/// attributes on a parameter apply to its field.
+///
+/// This lets a crate call Iris methods, such as vendor specific ones, that
+/// cornea has no binding for. The struct derives `serde::Serialize`, so the
+/// crate needs serde, with its `derive` feature, as a dependency:
+///
+/// ```
+/// pub mod foo {
+///     cornea::iris_rpc_fn!(bar "foo_bar"
+///         Bar {
+///             #[serde(rename = "instId")]
+///             id: u32,
+///             verbose: bool,
+///         } -> String
+///     );
+/// }
+/// ```
+#[macro_export]
 macro_rules! iris_rpc_fn {
     ($name:ident $method:literal $reqname:ident {$($(#[$reqattr: meta])? $reqident: ident: $reqty: ty),*} -> $resname:ty) => {
-        pub fn $name(fvp: &mut crate::iris_client::FastModelIris, $($reqident: $reqty),*) -> Result<$resname, std::io::Error> {
-            let resource_handle = fvp.send(crate::iris_client::RpcReq {
+        #[allow(clippy::too_many_arguments)]
+        pub fn $name(fvp: &mut $crate::iris_client::FastModelIris, $($reqident: $reqty),*) -> Result<$resname, $crate::error::CorneaError> {
+            let resource_handle = fvp.send($crate::iris_client::RpcReq {
                 method: $method,
                 params: &$reqname{
                     $($reqident),*
@@ -402,7 +1771,7 @@ macro_rules! iris_rpc_fn {
             $($(#[$reqattr])? pub $reqident: $reqty),*
         }
 
-        impl<'a> From<&'a $reqname> for crate::iris_client::RpcReq<'a, $reqname> {
+        impl<'a> From<&'a $reqname> for $crate::iris_client::RpcReq<'a, $reqname> {
             fn from(params: &'a $reqname) -> Self {
                 Self {
                     method: $method,
@@ -411,13 +1780,13 @@ macro_rules! iris_rpc_fn {
             }
         }
 
-        impl crate::iris_client::IrisOut for $reqname {
+        impl $crate::iris_client::IrisOut for $reqname {
             type Out = $resname;
         }
     };
 
     ($name:ident $method:literal $reqname:ident {$($(#[$reqattr: meta])? $reqident: ident: $reqty: ty,)*} -> $resname:ty) => {
-        iris_rpc_fn!($name $method
+        $crate::iris_rpc_fn!($name $method
             $reqname {
                 $($(#[$reqattr])? $reqident: $reqty),*
             } -> $resname
@@ -425,10 +1794,483 @@ macro_rules! iris_rpc_fn {
     };
 }
 
+pub mod recording {
+    //! Record the bytes of an Iris connection to replay them later, such as
+    //! to reproduce a bug seen on a model that is not at hand.
+    //!
+    //! A recording is a sequence of chunks, each a direction byte, `>` for
+    //! bytes sent to Iris and `<` for bytes received, then the length of
+    //! the chunk as a little endian u32 and the bytes themselves.
+
+    use std::collections::VecDeque;
+    use std::io::{Error as IOError, ErrorKind, Read, Write};
+    use std::time::Duration;
+
+    use crate::iris_client::Transport;
+
+    const SENT: u8 = b'>';
+    const RECEIVED: u8 = b'<';
+
+    /// A transport that copies everything sent and received over `inner`
+    /// into `log`.
+    ///
+    /// ```no_run
+    /// use cornea::recording::Recorder;
+    /// use cornea::FastModelIris;
+    /// use std::fs::File;
+    /// use std::net::TcpStream;
+    ///
+    /// let stream = TcpStream::connect("127.0.0.1:7100")?;
+    /// let log = File::create("session.iris")?;
+    /// let mut fvp = FastModelIris::from_stream(Recorder::new(stream, log))?;
+    /// fvp.register()?;
+    /// # Ok::<(), cornea::CorneaError>(())
+    /// ```
+    pub struct Recorder<T, W> {
+        inner: T,
+        log: W,
+    }
+
+    impl<T: Transport, W: Write> Recorder<T, W> {
+        pub fn new(inner: T, log: W) -> Self {
+            Self { inner, log }
+        }
+
+        fn record(&mut self, direction: u8, bytes: &[u8]) -> Result<(), IOError> {
+            if bytes.is_empty() {
+                return Ok(());
+            }
+            self.log.write_all(&[direction])?;
+            self.log.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            self.log.write_all(bytes)?;
+            self.log.flush()
+        }
+    }
+
+    impl<T: Transport, W: Write> Read for Recorder<T, W> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+            let len = self.inner.read(buf)?;
+            self.record(RECEIVED, &buf[..len])?;
+            Ok(len)
+        }
+    }
+
+    impl<T: Transport, W: Write> Write for Recorder<T, W> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+            let len = self.inner.write(buf)?;
+            self.record(SENT, &buf[..len])?;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> Result<(), IOError> {
+            self.inner.flush()
+        }
+    }
+
+    impl<T: Transport, W: Write> Transport for Recorder<T, W> {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IOError> {
+            self.inner.set_read_timeout(timeout)
+        }
+
+        fn set_nonblocking(&self, nonblocking: bool) -> Result<(), IOError> {
+            self.inner.set_nonblocking(nonblocking)
+        }
+
+        fn set_nodelay(&self, nodelay: bool) -> Result<(), IOError> {
+            self.inner.set_nodelay(nodelay)
+        }
+    }
+
+    /// A transport that plays back a recording made with `Recorder`.
+    ///
+    /// What is written has to match what was sent in the recording. The
+    /// bytes received in the recording are only readable once everything
+    /// sent before them has been written, so that responses come back in
+    /// the same order as they did. Reading earlier fails with `WouldBlock`,
+    /// and reading past the end of the recording finds the end of the
+    /// stream.
+    pub struct Replay {
+        chunks: VecDeque<(u8, Vec<u8>)>,
+    }
+
+    impl Replay {
+        /// Parse a recording made with `Recorder`
+        pub fn new<R: Read>(mut recording: R) -> Result<Self, IOError> {
+            let mut chunks = VecDeque::new();
+            let mut direction = [0; 1];
+            loop {
+                match recording.read_exact(&mut direction) {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+                if direction[0] != SENT && direction[0] != RECEIVED {
+                    return Err(IOError::new(
+                        ErrorKind::InvalidData,
+                        "Not an Iris recording",
+                    ));
+                }
+                let mut len = [0; 4];
+                recording.read_exact(&mut len)?;
+                let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+                recording.read_exact(&mut bytes)?;
+                chunks.push_back((direction[0], bytes));
+            }
+            Ok(Self { chunks })
+        }
+    }
+
+    impl Read for Replay {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+            let bytes = match self.chunks.front_mut() {
+                None => return Ok(0),
+                Some((SENT, _)) => return Err(ErrorKind::WouldBlock.into()),
+                Some((_, bytes)) => bytes,
+            };
+            let len = buf.len().min(bytes.len());
+            buf[..len].copy_from_slice(&bytes[..len]);
+            bytes.drain(..len);
+            if bytes.is_empty() {
+                self.chunks.pop_front();
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Replay {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+            let diverged = || {
+                IOError::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Sent {:?}, which the recording does not",
+                        String::from_utf8_lossy(buf)
+                    ),
+                )
+            };
+            let bytes = match self.chunks.front_mut() {
+                Some((SENT, bytes)) => bytes,
+                _ => return Err(diverged()),
+            };
+            let len = buf.len().min(bytes.len());
+            if buf[..len] != bytes[..len] {
+                return Err(diverged());
+            }
+            bytes.drain(..len);
+            if bytes.is_empty() {
+                self.chunks.pop_front();
+            }
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> Result<(), IOError> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Replay {
+        // Nothing to wait for: reads return at once either way
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> Result<(), IOError> {
+            Ok(())
+        }
+
+        fn set_nonblocking(&self, _nonblocking: bool) -> Result<(), IOError> {
+            Ok(())
+        }
+    }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::iris_client::tests::{response, Script};
+        use crate::FastModelIris;
+        use serde_json::{json, Value};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// A log that the test can still read after the recorder is gone
+        #[derive(Clone, Default)]
+        struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for SharedLog {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> Result<(), IOError> {
+                Ok(())
+            }
+        }
+
+        /// Register and make one call, returning what they got
+        fn session(fvp: &mut FastModelIris) -> (u32, Value) {
+            let id = fvp.register().unwrap();
+            let result = fvp.execute_raw("simulationTime_get", json!({})).unwrap();
+            (id, result)
+        }
+
+        #[test]
+        fn replay_gets_the_recorded_responses() {
+            let mut incoming =
+                b"IrisRpc/1.0 101 Switching Protocols\r\nSupported-Formats: IrisJson\r\n\r\n"
+                    .to_vec();
+            incoming.extend(response(0, json!({"instId": 7, "instName": "cornea"})));
+            incoming.extend(response(
+                0x7_0000_0001,
+                json!({"ticks": 42, "running": false}),
+            ));
+            let log = SharedLog::default();
+            let recorder = Recorder::new(Script::new(&incoming), log.clone());
+            let mut fvp = FastModelIris::from_stream(recorder).unwrap();
+            let recorded = session(&mut fvp);
+            drop(fvp);
+
+            let replay = Replay::new(&log.0.borrow()[..]).unwrap();
+            let mut fvp = FastModelIris::from_stream(replay).unwrap();
+            assert_eq!(session(&mut fvp), recorded);
+            assert_eq!(recorded.1, json!({"ticks": 42, "running": false}));
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub mod async_client {
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::marker::PhantomData;
+
+    use serde::Serialize;
+    use serde_json::Value;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpStream, ToSocketAddrs};
+
+    use crate::error::CorneaError;
+    use crate::instance_registry::{RegisterInstance, UnregisterInstance};
+    use crate::iris_client::{
+        check_handshake, closed_before_response, decode_frame, encode_frame, handshake,
+        next_msg_id, parse_frame, Format, Incoming, IrisOut, MessageHandle, RpcReq,
+    };
+
+    /// An Iris connection to a fast model for async code, using tokio. Unlike
+    /// `FastModelIris`, waiting for a response yields to the runtime instead
+    /// of blocking the thread.
+    ///
+    /// There are no callbacks; events are queued until they are taken with
+    /// `next_event`.
+    pub struct AsyncFastModelIris {
+        stream: TcpStream,
+        inst_id: Option<u32>,
+        current_msg_id: u32,
+        // Bytes received that are not yet part of a complete message
+        rx: Vec<u8>,
+        // Ids of the messages sent that have not had a response yet
+        in_flight: HashSet<u64>,
+        // Responses that arrived before anyone waited for them
+        pending: HashMap<u64, Result<Value, CorneaError>>,
+        // Events that arrived while waiting for a response
+        events: VecDeque<(String, Value)>,
+    }
+
+    impl AsyncFastModelIris {
+        /// Connect to the Iris server of a model listening on a local TCP port
+        pub async fn from_port(portnum: u16) -> Result<Self, CorneaError> {
+            Self::connect(("127.0.0.1", portnum)).await
+        }
+
+        /// Connect to the Iris server of a model listening at `addr`
+        pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, CorneaError> {
+            Ok(Self::from_stream(TcpStream::connect(addr).await?))
+        }
+
+        /// Talk to an Iris server over an already connected stream
+        pub fn from_stream(stream: TcpStream) -> Self {
+            // As for `FastModelIris`, don't delay the small Iris messages
+            let _ = stream.set_nodelay(true);
+            Self {
+                stream,
+                inst_id: None,
+                current_msg_id: 0,
+                rx: Vec::new(),
+                in_flight: HashSet::new(),
+                pending: HashMap::new(),
+                events: VecDeque::new(),
+            }
+        }
+
+        /// Negotiate the protocol with Iris and register as a component, like
+        /// `FastModelIris::register`.
+        pub async fn register(&mut self) -> Result<u32, CorneaError> {
+            self.register_as("cornea").await
+        }
+
+        /// Like `register`, but register under `name`, as
+        /// `FastModelIris::register_as`.
+        pub async fn register_as(&mut self, name: &str) -> Result<u32, CorneaError> {
+            self.stream.write_all(&handshake(Format::IrisJson)).await?;
+            let mut lines = Vec::new();
+            let response = loop {
+                match self.read_line().await? {
+                    Some(line) if line.is_empty() => break Some(lines),
+                    Some(line) => lines.push(line),
+                    None => break None,
+                }
+            };
+            check_handshake(response, Format::IrisJson)?;
+            let registration = self
+                .execute(&RegisterInstance {
+                    inst_name: name.to_string(),
+                    uniquify: true,
+                })
+                .await?;
+            self.inst_id = Some(registration.id);
+            Ok(registration.id)
+        }
+
+        /// The id Iris gave this connection in `register`
+        pub fn instance_id(&self) -> Option<u32> {
+            self.inst_id
+        }
+
+        /// Whether small messages are sent at once, which is the default
+        pub fn set_nodelay(&mut self, nodelay: bool) -> Result<(), CorneaError> {
+            self.stream.set_nodelay(nodelay)?;
+            Ok(())
+        }
+
+        /// Send a message to Iris, returning a handle to `wait` on
+        pub async fn send<'a, M: Serialize + 'a, I: Into<RpcReq<'a, M>>>(
+            &mut self,
+            message: I,
+        ) -> Result<MessageHandle<M>, CorneaError> {
+            let RpcReq { method, params } = message.into();
+            let id = next_msg_id(
+                self.inst_id,
+                &mut self.current_msg_id,
+                &self.in_flight,
+                &self.pending,
+            );
+            let frame = encode_frame(method, params, Some(id))?;
+            self.stream.write_all(&frame).await?;
+            self.in_flight.insert(id);
+            Ok(MessageHandle(id, PhantomData))
+        }
+
+        /// Wait for the response to a message. Events that arrive first are
+        /// queued for `next_event`, and responses to other messages are kept
+        /// for their own `wait`.
+        pub async fn wait<M: IrisOut>(
+            &mut self,
+            msg: MessageHandle<M>,
+        ) -> Result<<M as IrisOut>::Out, CorneaError> {
+            loop {
+                if let Some(result) = self.pending.remove(&msg.0) {
+                    return Ok(serde_json::from_value(result?)?);
+                }
+                match self.read_frame().await? {
+                    Some(payload) => self.route(&payload)?,
+                    None => return Err(closed_before_response()),
+                }
+            }
+        }
+
+        /// Execute an RPC with Iris within the Fast Model.
+        pub async fn execute<'a, M, I>(
+            &mut self,
+            message: I,
+        ) -> Result<<M as IrisOut>::Out, CorneaError>
+        where
+            M: Serialize + IrisOut + 'a,
+            I: Into<RpcReq<'a, M>>,
+        {
+            let handle = self.send(message).await?;
+            self.wait(handle).await
+        }
+
+        /// The next event from Iris, as its method and parameters, or `None`
+        /// once the connection closes. Responses that arrive in between are
+        /// kept for their own `wait`.
+        pub async fn next_event(&mut self) -> Result<Option<(String, Value)>, CorneaError> {
+            loop {
+                if let Some(event) = self.events.pop_front() {
+                    return Ok(Some(event));
+                }
+                match self.read_frame().await? {
+                    Some(payload) => self.route(&payload)?,
+                    None => return Ok(None),
+                }
+            }
+        }
+
+        /// Unregister from Iris and close the connection. Both are best
+        /// effort, as for `FastModelIris::close`, so a connection that the
+        /// server already hung up on closes without an error.
+        pub async fn close(mut self) -> Result<(), CorneaError> {
+            if let Some(id) = self.inst_id.take() {
+                let _ = self.execute(&UnregisterInstance { id }).await;
+            }
+            let _ = self.stream.shutdown().await;
+            Ok(())
+        }
+
+        fn route(&mut self, payload: &[u8]) -> Result<(), CorneaError> {
+            match decode_frame(payload)? {
+                Incoming::Event(method, params) => self.events.push_back((method, params)),
+                Incoming::Response(id, result) => {
+                    if self.in_flight.remove(&id) {
+                        self.pending.insert(id, result);
+                    } else {
+                        log_warn!("Received unexpected response: {} {:#?}", id, result);
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// Read more bytes into `rx`. Returns false when the connection is
+        /// closed.
+        async fn fill_rx(&mut self) -> Result<bool, CorneaError> {
+            let mut buf = [0; 4096];
+            let len = self.stream.read(&mut buf).await?;
+            self.rx.extend_from_slice(&buf[..len]);
+            Ok(len > 0)
+        }
+
+        async fn read_line(&mut self) -> Result<Option<String>, CorneaError> {
+            loop {
+                if let Some(end) = self.rx.iter().position(|&b| b == b'\n') {
+                    let mut line: Vec<u8> = self.rx.drain(..=end).collect();
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                    return String::from_utf8(line)
+                        .map(Some)
+                        .map_err(|e| CorneaError::Protocol(e.to_string()));
+                }
+                if !self.fill_rx().await? {
+                    return Ok(None);
+                }
+            }
+        }
+
+        async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, CorneaError> {
+            loop {
+                if let Some(payload) = parse_frame(&mut self.rx)? {
+                    return Ok(Some(payload));
+                }
+                if !self.fill_rx().await? {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
 pub mod instance_registry {
-    use crate::iris_client::AttributeInfo;
-    use serde::Deserialize;
+    use crate::error::CorneaError;
+    use crate::iris_client::{AttributeInfo, FastModelIris};
+    use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
+    use std::io::{Error as IOError, ErrorKind};
 
     iris_rpc_fn!(register_instance "instanceRegistry_registerInstance"
         RegisterInstance {
@@ -438,7 +2280,7 @@ pub mod instance_registry {
         } -> RegisterInstanceRes
     );
 
-    #[derive(Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Instance {
         #[serde(rename = "instId")]
         pub id: u32,
@@ -454,14 +2296,20 @@ pub mod instance_registry {
         pub id: u32,
     }
 
-    #[allow(unused)]
-    #[derive(Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug)]
     pub struct FunctionInfo {
-        args: HashMap<String, AttributeInfo>,
-        description: String,
-        retval: AttributeInfo,
+        pub args: HashMap<String, AttributeInfo>,
+        pub description: String,
+        pub retval: AttributeInfo,
     }
 
+    iris_rpc_fn!(unregister_instance "instanceRegistry_unregisterInstance"
+        UnregisterInstance {
+            #[serde(rename = "aInstId")]
+            id: u32,
+        } -> ()
+    );
+
     iris_rpc_fn!(list_instances "instanceRegistry_getList"
         ListInsnances { prefix: String } -> Vec<Instance>
     );
@@ -485,22 +2333,137 @@ pub mod instance_registry {
             prefix: String,
         } -> HashMap<String, FunctionInfo>
     );
+
+    /// Length in bytes of the dotted path components that every name starts
+    /// with. Only whole components count, so `cluster0.cpu1` and
+    /// `cluster0.cpu10` share `cluster0` and not `cluster0.cpu1`.
+    pub fn common_prefix_len<'a, I: IntoIterator<Item = &'a str>>(haystack: I) -> usize {
+        let mut haystack = haystack.into_iter();
+        let start = match haystack.next() {
+            Some(start) => start,
+            None => return 0,
+        };
+        let segments = |e: &str| {
+            e.split('.')
+                .zip(start.split('.'))
+                .take_while(|(x, y)| x == y)
+                .count()
+        };
+        let count = haystack.map(segments).min().unwrap_or(0);
+        start
+            .split('.')
+            .take(count)
+            .map(|seg| seg.len() + 1)
+            .sum::<usize>()
+            .min(start.len())
+    }
+
+    /// The name that Fast Models give their simulation engine
+    pub const SIMULATION_ENGINE: &str = "framework.SimulationEngine";
+
+    /// The simulation engines of the model, which are the instances whose
+    /// names end in a component starting with `SimulationEngine`. Models
+    /// usually have the one called `SIMULATION_ENGINE`.
+    pub fn simulation_engines(fvp: &mut FastModelIris) -> Result<Vec<Instance>, CorneaError> {
+        let mut engines = list_instances(fvp, String::new())?;
+        engines.retain(|inst| {
+            inst.name
+                .rsplit('.')
+                .next()
+                .is_some_and(|last| last.starts_with("SimulationEngine"))
+        });
+        Ok(engines)
+    }
+
+    /// The simulation engine named `name`, or without a name, the usual one
+    /// when the model has it and otherwise the first that it has.
+    pub fn simulation_engine(
+        fvp: &mut FastModelIris,
+        name: Option<&str>,
+    ) -> Result<Instance, CorneaError> {
+        if let Some(name) = name {
+            return get_instance_by_name(fvp, name.to_string());
+        }
+        match get_instance_by_name(fvp, SIMULATION_ENGINE.to_string()) {
+            Err(CorneaError::Rpc { .. }) => (),
+            res => return res,
+        }
+        simulation_engines(fvp)?.into_iter().next().ok_or_else(|| {
+            IOError::new(ErrorKind::NotFound, "The model has no simulation engine").into()
+        })
+    }
+
+    /// Find a component instance by name. The name may be the full instance
+    /// name, or the name with the prefix common to all components, such as
+    /// the name of the top level component, left off.
+    pub fn resolve(fvp: &mut FastModelIris, name: &str) -> Result<Instance, CorneaError> {
+        match get_instance_by_name(fvp, name.to_string()) {
+            Err(CorneaError::Rpc { .. }) => (),
+            res => return res,
+        }
+        let name = name.trim_start_matches('.');
+        let instance_list = list_instances(fvp, "component".to_string())?;
+        let prefix = common_prefix_len(instance_list.iter().map(|i| i.name.as_str()));
+        instance_list
+            .into_iter()
+            .find(|inst| inst.name[prefix..].trim_start_matches('.') == name)
+            .ok_or_else(|| {
+                IOError::new(ErrorKind::NotFound, format!("Instance {} not found", name)).into()
+            })
+    }
 }
 
 pub mod memory {
-    use crate::iris_client::AttributeInfo;
-    use serde::Deserialize;
+    use crate::error::CorneaError;
+    use crate::iris_client::{AttributeInfo, FastModelIris};
+    use serde::{Deserialize, Deserializer, Serialize};
     use serde_json::Value;
     use std::collections::HashMap;
+    use std::io::{Error as IOError, ErrorKind};
+    use std::str::FromStr;
 
-    #[derive(Deserialize, Debug)]
+    /// The byte order of a memory space
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Endianness {
+        #[serde(alias = "IRIS_ENDIANNESS_LITTLE")]
+        Little,
+        #[serde(alias = "IRIS_ENDIANNESS_BIG")]
+        Big,
+    }
+
+    impl FromStr for Endianness {
+        type Err = String;
+        /// Any of the spellings models use, ignoring case: "little" or
+        /// "big", with or without an `IRIS_ENDIANNESS_` prefix.
+        fn from_str(s: &str) -> Result<Self, String> {
+            let upper = s.to_ascii_uppercase();
+            match upper.trim_start_matches("IRIS_ENDIANNESS_") {
+                "LITTLE" => Ok(Self::Little),
+                "BIG" => Ok(Self::Big),
+                _ => Err(format!("{} is not little or big", s)),
+            }
+        }
+    }
+
+    // An endianness that no spelling matches is as good as none, rather than
+    // a reason to throw away the rest of the space.
+    fn lenient_endianness<'de, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Option<Endianness>, D::Error> {
+        let text = Option::<String>::deserialize(de)?;
+        Ok(text.and_then(|text| text.parse().ok()))
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct Space {
         pub attrib: Option<HashMap<String, AttributeInfo>>,
         pub attrib_defaults: Option<HashMap<String, AttributeInfo>>,
         pub cannonical_msn: Option<u64>,
         pub description: Option<String>,
-        pub endianness: Option<String>,
+        #[serde(default, deserialize_with = "lenient_endianness")]
+        pub endianness: Option<Endianness>,
         pub max_addr: Option<u64>,
         pub min_addr: Option<u64>,
         pub name: String,
@@ -515,6 +2478,121 @@ pub mod memory {
         } -> Vec<Space>
     );
 
+    /// Like `spaces`, but only asks the model the first time for each
+    /// instance, until `FastModelIris::invalidate_caches`.
+    pub fn cached_spaces(fvp: &mut FastModelIris, id: u32) -> Result<Vec<Space>, CorneaError> {
+        if let Some(spaces) = fvp.space_cache.get(&id) {
+            return Ok(spaces.clone());
+        }
+        let spaces = spaces(fvp, id)?;
+        fvp.space_cache.insert(id, spaces.clone());
+        Ok(spaces)
+    }
+
+    /// Format `buff`, read from `address`, as a hex dump of 16 bytes a line
+    /// in groups of `width` bytes, which is 1, 2, 4 or 8. Groups wider than a
+    /// byte are decoded big-endian when `big_endian` is set, and with `color`
+    /// the bytes that are not printable ASCII are dimmed in the gutter.
+    pub fn hex_dump(
+        address: u64,
+        buff: &[u8],
+        width: usize,
+        big_endian: bool,
+        color: bool,
+    ) -> String {
+        let mut out = match width {
+            2 => "         0    2    4    6    8    a    c    e\n",
+            4 => "         0        4        8        c\n",
+            8 => "         0                8\n",
+            _ => "         0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f\n",
+        }
+        .to_string();
+        let step = width.clamp(1, 8);
+        let addr_range = (address as usize)..(address as usize + buff.len());
+        let base = (address & !0xf) as usize;
+        for base_addr in (base..base + buff.len()).step_by(0x10) {
+            out.push_str(&format!("{:08x}", base_addr));
+            for cur_addr in (base_addr..base_addr + 0x10).step_by(step) {
+                if addr_range.contains(&cur_addr) {
+                    let offset = cur_addr - address as usize;
+                    let slice = &buff[offset..(offset + step).min(buff.len())];
+                    let word = if big_endian {
+                        slice.iter().fold(0, |w, &b| (w << 8) | u64::from(b))
+                    } else {
+                        slice.iter().rev().fold(0, |w, &b| (w << 8) | u64::from(b))
+                    };
+                    out.push_str(&format!(" {:0width$x}", word, width = step * 2));
+                } else {
+                    out.push_str(&format!(" {:width$}", "", width = step * 2));
+                }
+            }
+            out.push(' ');
+            for cur_addr in base_addr..base_addr + 0x10 {
+                if addr_range.contains(&cur_addr) {
+                    let byte = buff[cur_addr - address as usize];
+                    if byte.is_ascii_graphic() {
+                        out.push(char::from(byte));
+                    } else if color {
+                        out.push_str("\x1b[2m.\x1b[0m");
+                    } else {
+                        out.push('.');
+                    }
+                } else {
+                    out.push(' ');
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Whether the model describes memory space `space` of instance `id` as
+    /// big-endian. Spaces without an endianness count as little-endian.
+    pub fn is_big_endian(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+    ) -> Result<bool, CorneaError> {
+        Ok(cached_spaces(fvp, id)?
+            .iter()
+            .find(|s| s.id == space)
+            .and_then(|s| s.endianness)
+            == Some(Endianness::Big))
+    }
+
+    /// Check that the `len` bytes at `addr` lie inside memory space `space`
+    /// of instance `id`, so that an access outside it fails with a message
+    /// naming the space rather than the model's own. Bounds that the model
+    /// does not report are not checked.
+    pub fn check_bounds(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        addr: u64,
+        len: u64,
+    ) -> Result<(), CorneaError> {
+        let spaces = cached_spaces(fvp, id)?;
+        let space = match spaces.iter().find(|s| s.id == space) {
+            Some(space) => space,
+            None => return Ok(()),
+        };
+        let min = space.min_addr.unwrap_or(0);
+        let max = space.max_addr.unwrap_or(u64::MAX);
+        let last = addr.checked_add(len.saturating_sub(1));
+        let inside = addr >= min && matches!(last, Some(last) if last <= max);
+        if !inside {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "address 0x{:x} outside space {} (0x{:x}-0x{:x})",
+                    addr, space.name, min, max
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
     #[derive(Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
     pub struct ReadRes {
@@ -533,16 +2611,249 @@ pub mod memory {
                 #[serde(rename = "byteWidth")]
                 width: u64,
                 count: u64,
+                // Access attributes, by the names that the space's `attrib`
+                // lists, when not the space's `attrib_defaults`
+                #[serde(skip_serializing_if = "Option::is_none")]
+                attrib: Option<HashMap<String, Value>>,
+                // Whether the access may have the side effects that the
+                // target's own would, such as clearing a status register
+                // when it is read. Up to the model when not given.
+                #[serde(rename = "doSideEffects", skip_serializing_if = "Option::is_none")]
+                side_effects: Option<bool>,
             } -> ReadRes
     );
 
     #[derive(Deserialize, Debug)]
+    pub struct WriteRes {
+        pub error: Option<Value>,
+    }
+
+    iris_rpc_fn!(
+        write "memory_write"
+            MemoryWriteReq {
+                #[serde(rename = "instId")]
+                id: u32,
+                #[serde(rename = "spaceId")]
+                space: u64,
+                address: u64,
+                #[serde(rename = "byteWidth")]
+                width: u64,
+                count: u64,
+                data: Vec<u64>,
+                // As for `read`
+                #[serde(skip_serializing_if = "Option::is_none")]
+                attrib: Option<HashMap<String, Value>>,
+                #[serde(rename = "doSideEffects", skip_serializing_if = "Option::is_none")]
+                side_effects: Option<bool>,
+            } -> WriteRes
+    );
+
+    /// Write `bytes` to memory starting at `addr`, packing them into 64-bit
+    /// words the way Iris expects.
+    pub fn write_bytes(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        addr: u64,
+        bytes: &[u8],
+    ) -> Result<(), CorneaError> {
+        let data = bytes
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+        let res = write(
+            fvp,
+            id,
+            space,
+            addr,
+            1,
+            bytes.len() as u64,
+            data,
+            None,
+            None,
+        )?;
+        match res.error {
+            Some(error) => Err(IOError::other(format!(
+                "Write of {} bytes at {:#x} failed: {}",
+                bytes.len(),
+                addr,
+                error
+            ))
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of 64-bit words that `read_large` asks for in each request.
+    pub const READ_CHUNK_WORDS: u64 = 0x1000;
+
+    /// Read `len` bytes of memory starting at `addr`, using as many requests
+    /// as it takes to stay under the limit of what an Iris server will read
+    /// at once. Stops at the first request that reports an error, returning
+    /// that error along with the data read before it.
+    pub fn read_large(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        addr: u64,
+        len: u64,
+    ) -> Result<ReadRes, CorneaError> {
+        read_chunked(fvp, id, space, addr, len, READ_CHUNK_WORDS)
+    }
+
+    /// Like `read_large`, reading at most `chunk_words` 64-bit words per
+    /// request.
+    pub fn read_chunked(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        addr: u64,
+        len: u64,
+        chunk_words: u64,
+    ) -> Result<ReadRes, CorneaError> {
+        read_width_chunked(fvp, id, space, addr, 1, len, chunk_words, None)
+    }
+
+    /// Like `read_chunked`, reading `count` elements of `width` bytes each,
+    /// with `side_effects` as for `read`
+    #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
+    fn read_width_chunked(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        addr: u64,
+        width: u64,
+        count: u64,
+        chunk_words: u64,
+        side_effects: Option<bool>,
+    ) -> Result<ReadRes, CorneaError> {
+        // Whole words per chunk, so that each chunk's data lines up with the
+        // end of the one before it.
+        let chunk = (chunk_words.max(1).saturating_mul(8) / width.max(1)).max(1);
+        // `count` may come from the user, so only make room for one chunk
+        // up front
+        let first = chunk.min(count).saturating_mul(width);
+        let mut out = ReadRes {
+            data: Vec::with_capacity(first.div_ceil(8) as usize),
+            error: None,
+        };
+        let mut offset = 0;
+        while offset < count {
+            let n = chunk.min(count - offset);
+            let at = offset
+                .checked_mul(width)
+                .and_then(|offset| addr.checked_add(offset))
+                .ok_or_else(|| {
+                    IOError::new(
+                        ErrorKind::InvalidInput,
+                        format!("Read at {:#x} runs past the end of memory", addr),
+                    )
+                })?;
+            let res = read(fvp, id, space, at, width, n, None, side_effects)?;
+            out.data.extend(res.data);
+            if res.error.is_some() {
+                out.error = res.error;
+                break;
+            }
+            offset += n;
+        }
+        Ok(out)
+    }
+
+    /// Read `len` bytes of memory starting at `addr`. Iris packs the bytes
+    /// read into 64-bit words; this unpacks them and drops the padding at the
+    /// end of the last word.
+    pub fn read_bytes(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        addr: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, CorneaError> {
+        read_elements(fvp, id, space, addr, 1, len)
+    }
+
+    /// Read `count` elements of `width` bytes starting at `addr`, each with
+    /// a single access of that width, as some memory mapped registers need.
+    /// Iris returns the value of each element, so the bytes of an element
+    /// are least significant first whatever the endianness of the memory.
+    pub fn read_elements(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        addr: u64,
+        width: u64,
+        count: u64,
+    ) -> Result<Vec<u8>, CorneaError> {
+        read_elements_with(fvp, id, space, addr, width, count, None)
+    }
+
+    /// Like `read_elements`, but asks the model to read without side
+    /// effects, as a debugger should, so that reading a register that
+    /// clears when read leaves it alone. Models read as they always do
+    /// when they cannot help it.
+    pub fn peek_elements(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        addr: u64,
+        width: u64,
+        count: u64,
+    ) -> Result<Vec<u8>, CorneaError> {
+        read_elements_with(fvp, id, space, addr, width, count, Some(false))
+    }
+
+    fn read_elements_with(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        addr: u64,
+        width: u64,
+        count: u64,
+        side_effects: Option<bool>,
+    ) -> Result<Vec<u8>, CorneaError> {
+        let len = width.checked_mul(count).ok_or_else(|| {
+            IOError::new(
+                ErrorKind::InvalidInput,
+                format!("{} elements of {} bytes is too many to read", count, width),
+            )
+        })?;
+        let chunk = READ_CHUNK_WORDS;
+        let res = read_width_chunked(fvp, id, space, addr, width, count, chunk, side_effects)?;
+        let mut bytes: Vec<u8> = res.data.into_iter().flat_map(u64::to_le_bytes).collect();
+        if (bytes.len() as u64) < len {
+            return Err(IOError::other(format!(
+                "Read {} of {} bytes at {:#x}: {}",
+                bytes.len(),
+                len,
+                addr,
+                res.error.unwrap_or(Value::Null)
+            ))
+            .into());
+        }
+        bytes.truncate(len as usize);
+        Ok(bytes)
+    }
+
+    /// What the model knows about the memory around an address.
+    #[derive(Serialize, Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
     pub struct SidebandInfo {
+        /// First address of the region that contains the address.
         pub region_start: u64,
+        /// Last address of that region.
         pub region_end: u64,
+        /// Physical address that the address maps to.
         pub physical_address: u64,
+        /// Intermediate physical address, for a model with stage 2
+        /// translation.
         pub ipa: u64,
+        /// Whether instructions may not be fetched from the region.
         pub no_execute: bool,
     }
 
@@ -560,6 +2871,9 @@ pub mod memory {
     #[derive(Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
     pub struct AddressTranslation {
+        /// Translated addresses in the output space. Empty when the address
+        /// is not mapped.
+        #[serde(default)]
         pub address: Vec<u64>,
     }
 
@@ -569,18 +2883,34 @@ pub mod memory {
                 #[serde(rename = "instId")]
                 id: u32,
                 address: u64,
-                #[serde(rename = "spaceId")]
+                #[serde(rename = "inSpaceId")]
                 in_space: u64,
                 #[serde(rename = "outSpaceId")]
                 out_space: u64,
             } -> AddressTranslation
     );
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::iris_client::tests::{response, Script};
+        use serde_json::json;
+
+        #[test]
+        fn reads_stop_at_the_top_of_memory() {
+            let incoming = response(0, json!({"data": [1]}));
+            let mut fvp = FastModelIris::from_stream(Script::new(&incoming)).unwrap();
+            let res = read_width_chunked(&mut fvp, 1, 0, u64::MAX - 7, 8, 2, 1, None);
+            assert!(matches!(res, Err(CorneaError::Io(e)) if e.kind() == ErrorKind::InvalidInput));
+        }
+    }
 }
 
 pub mod breakpoint {
-    use crate::iris_client::FastModelIris;
+    use crate::error::CorneaError;
+    use crate::event;
+    use crate::iris_client::{FastModelIris, RwMode};
     use serde::{Deserialize, Serialize};
-    use std::io::Error as IOError;
+    use serde_json::Value;
 
     #[allow(unused)]
     #[derive(Deserialize, Debug)]
@@ -616,7 +2946,7 @@ pub mod breakpoint {
             id: u32,
             address: u64,
             #[serde(rename = "rwMode", skip_serializing_if = "Option::is_none")]
-            rw_mode: Option<String>,
+            rw_mode: Option<RwMode>,
             #[serde(skip_serializing_if = "Option::is_none")]
             size: Option<u64>,
             #[serde(rename = "spaceId",skip_serializing_if = "Option::is_none")]
@@ -630,6 +2960,71 @@ pub mod breakpoint {
         } -> u64
     );
 
+    /// A breakpoint hit, as reported by an `IRIS_BREAKPOINT_HIT` event.
+    /// The access fields are only there for data breakpoints.
+    #[derive(Deserialize, Debug, Clone)]
+    pub struct HitInfo {
+        #[serde(rename = "BPT_ID")]
+        pub id: u64,
+        /// The instance that hit the breakpoint, such as one core of a cluster
+        #[serde(skip)]
+        pub inst: Option<u32>,
+        #[serde(rename = "ACCESS_RW")]
+        pub kind: Option<RwMode>,
+        #[serde(rename = "ACCESS_ADDR")]
+        pub addr: Option<u64>,
+        #[serde(rename = "ACCESS_SIZE")]
+        pub size: Option<u64>,
+    }
+
+    impl HitInfo {
+        /// The kind and address of the access that triggered a data breakpoint
+        pub fn access(&self) -> Option<(RwMode, u64)> {
+            Some((self.kind?, self.addr?))
+        }
+    }
+
+    /// Decode the parameters of an `IRIS_BREAKPOINT_HIT` event, as passed to a
+    /// callback registered for it.
+    pub fn decode_hit(params: Value) -> Result<HitInfo, CorneaError> {
+        let inst = params
+            .get("sInstId")
+            .and_then(Value::as_u64)
+            .map(|i| i as u32);
+        let mut hit: HitInfo = event::decode_fields(params)?;
+        hit.inst = inst;
+        Ok(hit)
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct BreakpointInfo {
+        #[serde(rename = "bptId")]
+        pub id: u64,
+        #[serde(rename = "type")]
+        pub typ: Type,
+        pub address: Option<u64>,
+        pub size: Option<u64>,
+        pub space_id: Option<u64>,
+        pub rw_mode: Option<RwMode>,
+        /// Breakpoints that Iris reports without this flag are enabled.
+        #[serde(default = "enabled_by_default")]
+        pub enabled: bool,
+        #[serde(default)]
+        pub dont_stop: bool,
+    }
+
+    fn enabled_by_default() -> bool {
+        true
+    }
+
+    iris_rpc_fn!(list "breakpoint_getList"
+        GetList {
+            #[serde(rename = "instId")]
+            id: u32,
+        } -> Vec<BreakpointInfo>
+    );
+
     iris_rpc_fn!(delete "breakpoint_delete"
         Delete {
             #[serde(rename = "instId")]
@@ -639,6 +3034,60 @@ pub mod breakpoint {
         } -> ()
     );
 
+    // A register breakpoint names a resource in `rscId` instead of giving an
+    // address, so it has its own request.
+    iris_rpc_fn!(set_register "breakpoint_set"
+        SetRegister {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "rscId")]
+            resource_id: u64,
+            #[serde(rename = "rwMode", skip_serializing_if = "Option::is_none")]
+            rw_mode: Option<RwMode>,
+            #[serde(rename = "type")]
+            typ: Type,
+            #[serde(rename = "syncEc")]
+            sync: bool,
+            #[serde(rename = "dontStop")]
+            dont_stop: bool,
+        } -> u64
+    );
+
+    /// Set a breakpoint on accesses to a register, returning its id. With
+    /// `sync`, the breakpoint event is delivered before the simulation moves
+    /// on.
+    pub fn register(
+        fvp: &mut FastModelIris,
+        id: u32,
+        rsc_id: u64,
+        rw_mode: Option<RwMode>,
+        sync: bool,
+        dont_stop: bool,
+    ) -> Result<u64, CorneaError> {
+        set_register(fvp, id, rsc_id, rw_mode, Type::Register, sync, dont_stop)
+    }
+
+    iris_rpc_fn!(set_enabled "breakpoint_setBreakpointAttribute"
+        SetEnabled {
+            #[serde(rename = "instId")]
+            instance: u32,
+            #[serde(rename = "bptId")]
+            breakpoint: u64,
+            enabled: bool,
+        } -> ()
+    );
+
+    /// Re-enable a breakpoint that was disabled with `disable`.
+    pub fn enable(fvp: &mut FastModelIris, id: u32, bpt_id: u64) -> Result<(), CorneaError> {
+        set_enabled(fvp, id, bpt_id, true)
+    }
+
+    /// Stop a breakpoint from triggering without deleting it, such as while
+    /// stepping off the instruction it is set on.
+    pub fn disable(fvp: &mut FastModelIris, id: u32, bpt_id: u64) -> Result<(), CorneaError> {
+        set_enabled(fvp, id, bpt_id, false)
+    }
+
     pub fn code(
         fvp: &mut FastModelIris,
         id: u32,
@@ -646,7 +3095,7 @@ pub mod breakpoint {
         size: Option<u64>,
         space_id: u64,
         dont_stop: bool,
-    ) -> Result<u64, IOError> {
+    ) -> Result<u64, CorneaError> {
         set(
             fvp,
             id,
@@ -659,55 +3108,197 @@ pub mod breakpoint {
             false,
         )
     }
-}
+}
+
+pub mod checkpoint {
+    iris_rpc_fn!(save "checkpoint_save"
+        Save {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "checkpointDir")]
+            dir: String
+        } -> ()
+    );
+    iris_rpc_fn!(restore "checkpoint_restore"
+        Restore {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "checkpointDir")]
+            dir: String
+        } -> ()
+    );
+}
+
+pub mod step {
+    use serde::{Deserialize, Serialize};
+    use std::str::FromStr;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub enum Unit {
+        Instruction,
+        Cycle,
+    }
+
+    impl FromStr for Unit {
+        type Err = String;
+        fn from_str(s: &str) -> Result<Self, String> {
+            match s {
+                "instruction" => Ok(Self::Instruction),
+                "cycle" => Ok(Self::Cycle),
+                _ => Err(format!("{} is not instruction or cycle", s)),
+            }
+        }
+    }
+    iris_rpc_fn!(setup "step_setup"
+        Setup {
+            #[serde(rename = "instId")]
+            id: u32,
+            steps: u64,
+            unit: Unit
+        } -> ()
+    );
+    iris_rpc_fn!(remaining "step_getRemainingSteps"
+        Remain {
+            #[serde(rename = "instId")]
+            id: u32,
+            unit: Unit
+        } -> u64
+    );
+}
+
+pub mod disassembler {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Debug)]
+    pub struct Mode {
+        pub name: String,
+        pub description: Option<String>,
+    }
+
+    iris_rpc_fn!(modes "disassembler_getModes"
+        GetModes {
+            #[serde(rename = "instId")]
+            id: u32,
+        } -> Vec<Mode>
+    );
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Line {
+        pub address: u64,
+        /// The encoding of the instruction, as hex.
+        pub opcode: String,
+        #[serde(rename = "disass", alias = "disassembly")]
+        pub disassembly: String,
+    }
+
+    iris_rpc_fn!(disassembly "disassembler_getDisassembly"
+        GetDisassembly {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            mode: Option<String>,
+            #[serde(rename = "spaceId", skip_serializing_if = "Option::is_none")]
+            space: Option<u64>,
+            address: u64,
+            count: u64,
+        } -> Vec<Line>
+    );
+}
+
+pub mod table {
+    use crate::error::CorneaError;
+    use crate::iris_client::{FastModelIris, RwMode};
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Column {
+        pub name: String,
+        pub description: Option<String>,
+        #[serde(rename = "type")]
+        pub typ: Option<String>,
+        pub bit_width: Option<u64>,
+        pub rw_mode: Option<RwMode>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TableInfo {
+        #[serde(rename = "tableId")]
+        pub id: u64,
+        pub name: String,
+        pub description: Option<String>,
+        pub min_index: Option<u64>,
+        pub max_index: Option<u64>,
+        pub columns: Vec<Column>,
+    }
 
-pub mod checkpoint {
-    iris_rpc_fn!(save "checkpoint_save"
-        Save {
-            #[serde(rename = "instId")]
-            id: u32,
-            #[serde(rename = "checkpointDir")]
-            dir: String
-        } -> ()
-    );
-    iris_rpc_fn!(restore "checkpoint_restore"
-        Restore {
+    iris_rpc_fn!(info "table_getTableInfo"
+        GetTableInfo {
             #[serde(rename = "instId")]
             id: u32,
-            #[serde(rename = "checkpointDir")]
-            dir: String
-        } -> ()
+        } -> Vec<TableInfo>
     );
-}
-
-pub mod step {
-    use serde::{Deserialize, Serialize};
 
+    /// One row of a table, with a value for each column by name.
     #[derive(Serialize, Deserialize, Debug)]
-    #[serde(rename_all = "camelCase")]
-    pub enum Unit {
-        Instruction,
-        Cycle,
+    pub struct TableRecord {
+        pub index: u64,
+        pub row: HashMap<String, Value>,
     }
-    iris_rpc_fn!(setup "step_setup"
-        Setup {
+
+    #[derive(Deserialize, Debug)]
+    pub struct TableRead {
+        pub data: Vec<TableRecord>,
+        pub error: Option<Value>,
+    }
+
+    iris_rpc_fn!(read_records "table_read"
+        Read {
             #[serde(rename = "instId")]
             id: u32,
-            steps: u64,
-            unit: Unit
-        } -> ()
+            #[serde(rename = "tableId")]
+            table_id: u64,
+            index: u64,
+            count: u64,
+        } -> TableRead
     );
-    iris_rpc_fn!(remaining "step_getRemainingSteps"
-        Remain {
+
+    /// Read the row at `index` of a table.
+    pub fn read(
+        fvp: &mut FastModelIris,
+        id: u32,
+        table_id: u64,
+        index: u64,
+    ) -> Result<TableRead, CorneaError> {
+        read_records(fvp, id, table_id, index, 1)
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct TableWrite {
+        pub error: Option<Value>,
+    }
+
+    iris_rpc_fn!(write "table_write"
+        Write {
             #[serde(rename = "instId")]
             id: u32,
-            unit: Unit
-        } -> u64
+            #[serde(rename = "tableId")]
+            table_id: u64,
+            records: Vec<TableRecord>,
+        } -> TableWrite
     );
 }
 
 pub mod simulation_time {
+    use crate::error::CorneaError;
+    use crate::iris_client::FastModelIris;
     use serde::Deserialize;
+    use std::thread::sleep;
+    use std::time::Duration;
 
     #[derive(Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
@@ -716,12 +3307,52 @@ pub mod simulation_time {
         pub tick_hz: u64,
         pub running: bool,
     }
+
+    impl Time {
+        /// The simulation time in seconds. Models that have not yet run may
+        /// report a `tick_hz` of 0, which gives 0 seconds.
+        pub fn seconds(&self) -> f64 {
+            if self.tick_hz == 0 {
+                return 0.0;
+            }
+            self.ticks as f64 / self.tick_hz as f64
+        }
+
+        /// The simulation time as a `Duration`, truncated to the nanosecond.
+        pub fn duration(&self) -> Duration {
+            if self.tick_hz == 0 {
+                return Duration::ZERO;
+            }
+            let secs = self.ticks / self.tick_hz;
+            let rem = u128::from(self.ticks % self.tick_hz);
+            let nanos = rem * 1_000_000_000 / u128::from(self.tick_hz);
+            Duration::new(secs, nanos as u32)
+        }
+    }
+
     iris_rpc_fn!(run "simulationTime_run"
         Run {
             #[serde(rename = "instId")]
             id: u32
         } -> ()
     );
+    // Only models that record their execution for reverse debugging
+    // provide this; see `supports_reverse`. Like `run`, it returns once the
+    // simulation runs, backwards until a breakpoint or the start of the
+    // recording.
+    iris_rpc_fn!(run_reverse "simulationTime_runReverse"
+        RunReverse {
+            #[serde(rename = "instId")]
+            id: u32
+        } -> ()
+    );
+
+    /// Whether the simulation engine `id` can run backwards with
+    /// `run_reverse`. `step::setup` then steps backwards too.
+    pub fn supports_reverse(fvp: &mut FastModelIris, id: u32) -> Result<bool, CorneaError> {
+        fvp.supports(id, "simulationTime_runReverse")
+    }
+
     iris_rpc_fn!(stop "simulationTime_stop"
         Stop {
             #[serde(rename = "instId")]
@@ -734,6 +3365,35 @@ pub mod simulation_time {
             id: u32
         } -> Time
     );
+
+    /// How often `run_until` checks the simulation time
+    const RUN_UNTIL_POLL: Duration = Duration::from_millis(1);
+
+    /// Run the simulation until it reaches `ticks` and stop it. The model
+    /// only stops between quanta, so the returned time may be past `ticks`.
+    /// This also returns when the simulation stops for another reason, such
+    /// as a breakpoint, before reaching `ticks`.
+    pub fn run_until(fvp: &mut FastModelIris, id: u32, ticks: u64) -> Result<Time, CorneaError> {
+        let now = get(fvp, id)?;
+        if now.ticks >= ticks {
+            return Ok(now);
+        }
+        run(fvp, id)?;
+        loop {
+            let now = get(fvp, id)?;
+            if !now.running {
+                return Ok(now);
+            }
+            if now.ticks >= ticks {
+                stop(fvp, id)?;
+                while get(fvp, id)?.running {
+                    sleep(RUN_UNTIL_POLL);
+                }
+                return get(fvp, id);
+            }
+            sleep(RUN_UNTIL_POLL);
+        }
+    }
 }
 
 pub mod simulation {
@@ -754,6 +3414,16 @@ pub mod simulation {
 }
 
 pub mod event_stream {
+    use crate::error::CorneaError;
+    use crate::iris_client::{FastModelIris, IrisOut, RpcReq};
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+
+    // `disable` creates the stream without sending anything until it is
+    // enabled, `ringBuffer` keeps the events in a buffer on the model's side
+    // instead of sending them, and `stop` stops the simulation each time the
+    // stream sends an event. `subscribe` and `subscribe_stopping` cover the
+    // usual cases without the row of bools.
     iris_rpc_fn!(create "eventStream_create"
         Create {
             #[serde(rename = "instId", skip_serializing_if = "Option::is_none")]
@@ -769,6 +3439,74 @@ pub mod event_stream {
         } -> u64
     );
 
+    /// Create a stream that sends each event of `source` on instance `id`,
+    /// or of the simulation as a whole for `None`, to `to_id` as it happens.
+    /// The simulation carries on running.
+    pub fn subscribe(
+        fvp: &mut FastModelIris,
+        id: Option<u32>,
+        to_id: u32,
+        source: u32,
+    ) -> Result<u64, CorneaError> {
+        create(fvp, id, false, to_id, source, false, false)
+    }
+
+    /// Like `subscribe`, but the simulation stops each time the stream sends
+    /// an event. On an `IRIS_BREAKPOINT_HIT` stream, this stops at every hit,
+    /// including those of breakpoints set with `dont_stop`.
+    pub fn subscribe_stopping(
+        fvp: &mut FastModelIris,
+        id: Option<u32>,
+        to_id: u32,
+        source: u32,
+    ) -> Result<u64, CorneaError> {
+        create(fvp, id, false, to_id, source, false, true)
+    }
+
+    #[derive(Serialize)]
+    struct CreateCounter {
+        #[serde(rename = "instId")]
+        id: u32,
+        #[serde(rename = "ecInstId")]
+        to_id: u32,
+        #[serde(rename = "evSrcId")]
+        source: u32,
+        counter: bool,
+    }
+
+    impl IrisOut for CreateCounter {
+        type Out = u64;
+    }
+
+    /// Create a stream that counts the events of `source` on instance `id`
+    /// instead of sending each one to `to_id`, starting from zero. `counter`
+    /// reads the count, and `destroy` removes the stream as for any other.
+    pub fn create_counter(
+        fvp: &mut FastModelIris,
+        id: u32,
+        to_id: u32,
+        source: u32,
+    ) -> Result<u64, CorneaError> {
+        fvp.execute(RpcReq {
+            method: "eventStream_create",
+            params: &CreateCounter {
+                id,
+                to_id,
+                source,
+                counter: true,
+            },
+        })
+    }
+
+    iris_rpc_fn!(counter "eventStream_getCounter"
+        GetCounter {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "esId")]
+            es_id: u64,
+        } -> u64
+    );
+
     iris_rpc_fn!(trace_ranges "eventStream_setTraceRanges"
         TraceRanges {
             #[serde(rename = "instId")]
@@ -779,12 +3517,74 @@ pub mod event_stream {
             ranges: Vec<u64>,
         } -> ()
     );
+
+    iris_rpc_fn!(destroy "eventStream_destroy"
+        Destroy {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "esId")]
+            es_id: u64,
+        } -> ()
+    );
+
+    iris_rpc_fn!(enable "eventStream_enable"
+        Enable {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "esId")]
+            es_id: u64,
+        } -> ()
+    );
+
+    iris_rpc_fn!(disable "eventStream_disable"
+        Disable {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "esId")]
+            es_id: u64,
+        } -> ()
+    );
+
+    /// One event recorded by a stream created with `buffer` set
+    #[derive(Deserialize, Debug)]
+    pub struct EventRecord {
+        #[serde(rename = "esId")]
+        pub es_id: u64,
+        #[serde(rename = "sInstId")]
+        pub source_inst: Option<u32>,
+        /// Simulation time at which the event fired, in ticks
+        pub time: Option<u64>,
+        /// The event's fields, keyed by name as in the same event delivered to
+        /// a callback
+        pub fields: Value,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct RingBuffer {
+        #[serde(alias = "data")]
+        pub events: Vec<EventRecord>,
+        /// The buffer filled and the oldest events were overwritten
+        #[serde(default, alias = "wrapped")]
+        pub overflow: bool,
+    }
+
+    iris_rpc_fn!(ring_buffer "eventStream_getRingBuffer"
+        GetRingBuffer {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "esId")]
+            es_id: u64,
+        } -> RingBuffer
+    );
 }
 
 pub mod event {
-    use serde::Deserialize;
+    use crate::error::CorneaError;
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug)]
     pub struct Field {
         pub name: String,
         #[serde(rename = "type")]
@@ -793,7 +3593,7 @@ pub mod event {
         pub description: Option<String>,
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug)]
     pub struct SourceInfo {
         pub description: Option<String>,
         pub name: String,
@@ -809,12 +3609,33 @@ pub mod event {
     iris_rpc_fn!(sources "event_getEventSources"
         Sources { #[serde(rename = "instId")] id: u32, } -> Vec<SourceInfo>
     );
+
+    /// Decode the fields of an event, as passed to a callback, into `T`.
+    ///
+    /// The fields are keyed by the names that `source` reports for them, which
+    /// are usually upper case, so `T` will want a `#[serde(rename)]` on each
+    /// of its fields, such as `#[serde(rename = "BPT_ID")]` for the id of the
+    /// breakpoint in an `IRIS_BREAKPOINT_HIT` event.
+    pub fn decode_fields<T: DeserializeOwned>(mut params: Value) -> Result<T, CorneaError> {
+        match params.get_mut("fields") {
+            Some(fields) => Ok(serde_json::from_value(fields.take())?),
+            None => Err(CorneaError::Protocol(format!(
+                "Event without fields: {}",
+                params
+            ))),
+        }
+    }
 }
 
 pub mod resource {
-    use serde::Deserialize;
+    use crate::error::CorneaError;
+    use crate::iris_client::{FastModelIris, RwMode};
+    use serde::{Deserialize, Serialize};
     use serde_json::Value;
-    #[derive(Deserialize, Debug)]
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::{Error as IOError, ErrorKind};
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct ResourceInfo {
         #[serde(rename = "bitWidth")]
         pub bit_width: u64,
@@ -825,11 +3646,56 @@ pub mod resource {
         #[serde(rename = "rscId")]
         pub id: u64,
         #[serde(rename = "parameterInfo")]
-        pub parameter_info: Option<Value>,
+        pub parameter_info: Option<ParameterInfo>,
         #[serde(rename = "registerInfo")]
-        pub register_info: Option<Value>,
+        pub register_info: Option<RegisterInfo>,
+        /// How the value is represented, such as "numeric" or "string". Iris
+        /// leaves this out for plain numeric resources.
+        #[serde(rename = "type")]
+        pub data_type: Option<String>,
         #[serde(rename = "rwMode")]
-        pub rw_mode: Option<String>,
+        pub rw_mode: Option<RwMode>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RegisterInfo {
+        /// Offset of a memory mapped register within its peripheral.
+        pub address_offset: Option<u64>,
+        /// Register number in the canonical numbering for this kind of
+        /// instance, such as the DWARF register number for a core.
+        #[serde(rename = "canonicalRn")]
+        pub canonical_number: Option<u64>,
+        /// Bit offset of a register field within its parent register.
+        pub lsb_offset: Option<u64>,
+        /// Value after reset, as words of the register's width.
+        #[serde(rename = "resetData")]
+        pub reset_value: Option<Vec<u64>>,
+        pub is_pseudo_register: Option<bool>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ParameterInfo {
+        /// Value the parameter has unless set otherwise.
+        #[serde(rename = "defaultData")]
+        pub default_value: Option<Vec<u64>>,
+        /// Default of a string-valued parameter.
+        pub default_string: Option<String>,
+        /// The values the parameter may take, when it is an enumeration.
+        #[serde(default)]
+        pub enums: Vec<EnumElement>,
+        /// Whether the parameter may only be set when the model starts.
+        pub init_only: Option<bool>,
+        pub min: Option<Vec<u64>>,
+        pub max: Option<Vec<u64>>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct EnumElement {
+        pub value: Value,
+        pub symbol: String,
+        pub description: Option<String>,
     }
 
     iris_rpc_fn!(get_list "resource_getList"
@@ -839,13 +3705,52 @@ pub mod resource {
             #[serde(skip_serializing_if = "Option::is_none")]
             group: Option<String>,
             #[serde(rename = "rscId", skip_serializing_if = "Option::is_none")]
-            resource_id: Option<u32>,
+            resource_id: Option<u64>,
         } -> Vec<ResourceInfo>
     );
 
+    /// A named set of an instance's resources, such as a bank of registers,
+    /// which `get_list` can be limited to.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ResourceGroup {
+        pub name: String,
+        pub description: Option<String>,
+        #[serde(rename = "rscIds", default)]
+        pub ids: Vec<u64>,
+    }
+
+    iris_rpc_fn!(groups "resource_getListOfResourceGroups"
+        GetGroups {
+            #[serde(rename = "instId")]
+            id: u32,
+        } -> Vec<ResourceGroup>
+    );
+
     #[derive(Deserialize, Debug)]
     pub struct ResourceRead {
+        /// The values of the numeric resources read, in the order asked for
         pub data: Vec<u64>,
+        /// The values of the string resources read, in the order asked for
+        #[serde(default)]
+        pub strings: Vec<String>,
+    }
+
+    /// The value of one resource, as returned by `read_values`
+    #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+    #[serde(untagged)]
+    pub enum ResourceValue {
+        /// As many words as the resource's width needs, least significant
+        /// first
+        Numeric(Vec<u64>),
+        Str(String),
+    }
+
+    impl ResourceInfo {
+        /// Whether the value of this resource is a string rather than a
+        /// number
+        pub fn is_string(&self) -> bool {
+            self.data_type.as_deref() == Some("string")
+        }
     }
 
     iris_rpc_fn!(read "resource_read"
@@ -856,7 +3761,323 @@ pub mod resource {
             resource_ids: Vec<u64>,
         } -> ResourceRead
     );
+
+    #[derive(Deserialize, Debug)]
+    pub struct ResourceWrite {
+        pub error: Option<Value>,
+    }
+
+    // Only for resources with numeric values; string-valued resources, such
+    // as string parameters, are written with `strings` rather than `data`.
+    iris_rpc_fn!(write "resource_write"
+        Write {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "rscIds")]
+            resource_ids: Vec<u64>,
+            data: Vec<u64>,
+        } -> ResourceWrite
+    );
+
+    /// Write `value` to a single numeric resource, checking first that the
+    /// resource is not read only.
+    pub fn write_by_id(
+        fvp: &mut FastModelIris,
+        id: u32,
+        resource_id: u64,
+        value: u64,
+    ) -> Result<ResourceWrite, CorneaError> {
+        let info = get_list(fvp, id, None, Some(resource_id))?;
+        if let Some(ResourceInfo {
+            name,
+            rw_mode: Some(RwMode::Read),
+            ..
+        }) = info.first()
+        {
+            return Err(IOError::new(
+                ErrorKind::PermissionDenied,
+                format!("Resource {} is read only", name),
+            )
+            .into());
+        }
+        write(fvp, id, vec![resource_id], vec![value])
+    }
+
+    /// The resources of instance `id`. They are read from the model the
+    /// first time and cached until `FastModelIris::invalidate_caches`.
+    pub fn cached_list(fvp: &mut FastModelIris, id: u32) -> Result<Vec<ResourceInfo>, CorneaError> {
+        if let Some(resources) = fvp.resource_cache.get(&id) {
+            return Ok(resources.clone());
+        }
+        let resources = get_list(fvp, id, None, None)?;
+        fvp.resource_cache.insert(id, resources.clone());
+        Ok(resources)
+    }
+
+    /// Read the numeric resources of instance `id` called `names` in a single
+    /// request. Each value is as many words as the resource's width needs,
+    /// least significant first. Names the instance does not have are left
+    /// out of the result.
+    pub fn read_named<S: AsRef<str>>(
+        fvp: &mut FastModelIris,
+        id: u32,
+        names: &[S],
+    ) -> Result<HashMap<String, Vec<u64>>, CorneaError> {
+        let wanted: Vec<_> = cached_list(fvp, id)?
+            .into_iter()
+            .filter(|r| !r.is_string() && names.iter().any(|n| n.as_ref() == r.name))
+            .collect();
+        let values = read_values(fvp, id, &wanted)?;
+        let mut named = HashMap::new();
+        for (res, value) in wanted.into_iter().zip(values) {
+            if let ResourceValue::Numeric(words) = value {
+                named.insert(res.name, words);
+            }
+        }
+        Ok(named)
+    }
+
+    /// Read every numeric register of instance `id` that can be read, in a
+    /// single request, for a snapshot of the core. Registers wider than 64
+    /// bits keep their least significant word.
+    pub fn dump_registers(
+        fvp: &mut FastModelIris,
+        id: u32,
+    ) -> Result<BTreeMap<String, u64>, CorneaError> {
+        let wanted: Vec<_> = cached_list(fvp, id)?
+            .into_iter()
+            .filter(|r| {
+                r.parameter_info.is_none() && !r.is_string() && r.rw_mode != Some(RwMode::Write)
+            })
+            .collect();
+        let values = read_values(fvp, id, &wanted)?;
+        let mut dump = BTreeMap::new();
+        for (res, value) in wanted.into_iter().zip(values) {
+            if let ResourceValue::Numeric(words) = value {
+                dump.insert(res.name, words.first().copied().unwrap_or(0));
+            }
+        }
+        Ok(dump)
+    }
+
+    /// Read `resources` of instance `id` in a single request, numeric or
+    /// string according to each one's declared type. Values missing from
+    /// the response are left out of the end of the result.
+    pub fn read_values(
+        fvp: &mut FastModelIris,
+        id: u32,
+        resources: &[ResourceInfo],
+    ) -> Result<Vec<ResourceValue>, CorneaError> {
+        if resources.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Iris returns the numbers and the strings in separate lists, each
+        // in the order asked for. A number takes as many words as its
+        // width needs.
+        let val = read(fvp, id, resources.iter().map(|r| r.id).collect())?;
+        let mut strings = val.strings.into_iter();
+        let mut offset = 0;
+        let mut values = Vec::new();
+        for res in resources {
+            let value = if res.is_string() {
+                strings.next().map(ResourceValue::Str)
+            } else {
+                let len = res.bit_width.div_ceil(64).max(1) as usize;
+                let words = val.data.get(offset..offset + len);
+                offset += len;
+                words.map(|words| ResourceValue::Numeric(words.to_vec()))
+            };
+            match value {
+                Some(value) => values.push(value),
+                None => break,
+            }
+        }
+        Ok(values)
+    }
+
+    /// Write `value` to the numeric resource of instance `id` called `name`,
+    /// checking first that the resource exists and is not read only.
+    pub fn write_named(
+        fvp: &mut FastModelIris,
+        id: u32,
+        name: &str,
+        value: u64,
+    ) -> Result<ResourceWrite, CorneaError> {
+        let res = cached_list(fvp, id)?
+            .into_iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| {
+                IOError::new(ErrorKind::NotFound, format!("Resource {} not found", name))
+            })?;
+        if res.rw_mode == Some(RwMode::Read) {
+            return Err(IOError::new(
+                ErrorKind::PermissionDenied,
+                format!("Resource {} is read only", name),
+            )
+            .into());
+        }
+        write(fvp, id, vec![res.id], vec![value])
+    }
+}
+
+pub mod console {
+    use crate::error::CorneaError;
+    use crate::event::{self, SourceInfo};
+    use crate::iris_client::FastModelIris;
+    use serde_json::Value;
+
+    /// Event sources that carry text written by the target, in the order
+    /// that `source` tries them.
+    pub const SOURCES: &[&str] = &["IRIS_SEMIHOSTING_OUTPUT"];
+
+    /// Fields that hold the text of an event, in order of preference
+    const TEXT_FIELDS: &[&str] = &["DATA", "CHAR", "CHARACTER", "VALUE"];
+
+    /// The first of `SOURCES` that instance `id` has, if any.
+    pub fn source(fvp: &mut FastModelIris, id: u32) -> Result<Option<SourceInfo>, CorneaError> {
+        for name in SOURCES {
+            match event::source(fvp, id, name.to_string()) {
+                Ok(source) => return Ok(Some(source)),
+                Err(CorneaError::Rpc { .. }) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decode the text carried by an event, as passed to a callback. A
+    /// string field is taken as is, a number as a single character, and an
+    /// array as bytes packed into 64-bit words, cut short by a `SIZE` field
+    /// when there is one.
+    pub fn decode_text(params: &Value) -> Option<String> {
+        let fields = params.get("fields")?;
+        let value = TEXT_FIELDS
+            .iter()
+            .find_map(|name| fields.get(*name))
+            .or_else(|| fields.as_object()?.values().find(|v| v.is_string()))?;
+        match value {
+            Value::String(text) => Some(text.clone()),
+            Value::Number(n) => Some(char::from(n.as_u64()? as u8).to_string()),
+            Value::Array(words) => {
+                let mut bytes: Vec<u8> = words
+                    .iter()
+                    .filter_map(Value::as_u64)
+                    .flat_map(u64::to_le_bytes)
+                    .collect();
+                match fields.get("SIZE").and_then(Value::as_u64) {
+                    Some(size) => bytes.truncate(size as usize),
+                    None => {
+                        while bytes.last() == Some(&0) {
+                            bytes.pop();
+                        }
+                    }
+                }
+                Some(String::from_utf8_lossy(&bytes).into_owned())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "elf")]
+pub mod elf {
+    use crate::error::CorneaError;
+    use addr2line::gimli::{self, EndianSlice, RunTimeEndian};
+    use object::{Architecture, Object, ObjectSection, ObjectSymbol, SymbolKind};
+    use std::io::{Error as IOError, ErrorKind};
+    use std::path::Path;
+
+    /// Where an address is in the source, from the debug info of an ELF
+    #[derive(Debug, Clone)]
+    pub struct SourceLocation {
+        pub function: Option<String>,
+        pub file: Option<String>,
+        pub line: Option<u32>,
+    }
+
+    /// An ELF file, such as the program running on an instance, to look up
+    /// symbols in.
+    pub struct Elf {
+        data: Vec<u8>,
+    }
+
+    impl Elf {
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, CorneaError> {
+            let data = std::fs::read(path)?;
+            object::File::parse(&*data).map_err(invalid_data)?;
+            Ok(Self { data })
+        }
+
+        fn file(&self) -> object::File<'_> {
+            // Checked by `open`
+            object::File::parse(&*self.data).unwrap()
+        }
+
+        /// The address of the symbol `name`. The address of a Thumb function
+        /// has bit 0 set to mark its instruction set; that bit is cleared, so
+        /// the address is that of the first instruction.
+        pub fn symbol(&self, name: &str) -> Option<u64> {
+            let file = self.file();
+            let thumb = file.architecture() == Architecture::Arm;
+            let sym = file
+                .symbols()
+                .find(|s| s.is_definition() && s.name() == Ok(name))?;
+            if thumb && sym.kind() == SymbolKind::Text {
+                Some(sym.address() & !1)
+            } else {
+                Some(sym.address())
+            }
+        }
+
+        /// The function containing `addr`, and its file and line when the ELF
+        /// has line number debug info.
+        pub fn locate(&self, addr: u64) -> Result<SourceLocation, CorneaError> {
+            let file = self.file();
+            let thumb = file.architecture() == Architecture::Arm;
+            let function = file
+                .symbols()
+                .filter(|s| s.kind() == SymbolKind::Text)
+                .find(|s| {
+                    let start = if thumb { s.address() & !1 } else { s.address() };
+                    (start..start + s.size().max(1)).contains(&addr)
+                })
+                .and_then(|s| s.name().ok().map(str::to_string));
+            let endian = if file.is_little_endian() {
+                RunTimeEndian::Little
+            } else {
+                RunTimeEndian::Big
+            };
+            let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+                let data = file
+                    .section_by_name(id.name())
+                    .and_then(|s| s.data().ok())
+                    .unwrap_or(&[]);
+                Ok(EndianSlice::new(data, endian))
+            })
+            .map_err(invalid_dwarf)?;
+            let context = addr2line::Context::from_dwarf(dwarf).map_err(invalid_dwarf)?;
+            let location = context.find_location(addr).map_err(invalid_dwarf)?;
+            Ok(SourceLocation {
+                function,
+                file: location.as_ref().and_then(|l| l.file.map(str::to_string)),
+                line: location.and_then(|l| l.line),
+            })
+        }
+    }
+
+    fn invalid_data(e: object::Error) -> CorneaError {
+        IOError::new(ErrorKind::InvalidData, e.to_string()).into()
+    }
+
+    fn invalid_dwarf(e: gimli::Error) -> CorneaError {
+        IOError::new(ErrorKind::InvalidData, e.to_string()).into()
+    }
 }
 
-pub use iris_client::FastModelIris;
+#[cfg(feature = "tokio")]
+pub use async_client::AsyncFastModelIris;
+pub use error::{CorneaError, RpcError};
+pub use iris_client::{
+    CallbackFlow, CallbackToken, Events, FastModelIris, FastModelIrisBuilder, Format, RwMode,
+};
 pub mod gdb;
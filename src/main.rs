@@ -1,15 +1,24 @@
-use std::convert::TryInto;
-use std::io::{stdin, stdout};
-use std::iter;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{stdin, stdout, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::rc::Rc;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use clap::{Parser, Subcommand};
+use gdbstub::target::Target;
 use gdbstub::GdbStub;
+use serde::Serialize;
 
+use cornea::gdb::t32::{GdbOverPipe, GdbOverTcp};
+use cornea::resource::ResourceValue;
 #[allow(unused)]
 use cornea::{
-    breakpoint, checkpoint, event, event_stream, instance_registry, memory, resource, simulation,
-    simulation_time, step, FastModelIris,
+    breakpoint, checkpoint, console, disassembler, event, event_stream, instance_registry, memory,
+    resource, simulation, simulation_time, step, CallbackFlow, CorneaError, FastModelIris, RwMode,
 };
 
 #[derive(Parser, Debug)]
@@ -18,6 +27,16 @@ struct Cli {
     command: Command,
     #[clap(short, long)]
     port: Option<u16>,
+    /// The machine the model runs on, by host name or IP address
+    #[clap(long, default_value = "127.0.0.1")]
+    host: String,
+    /// The simulation engine to run, for models with more than one or with
+    /// one under another name than framework.SimulationEngine
+    #[clap(long, global = true)]
+    engine: Option<String>,
+    /// Print results as JSON instead of a table
+    #[clap(long, global = true)]
+    json: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -28,8 +47,15 @@ enum Command {
     EventFields(ResourceReadArgs),
     /// Log events as they occur
     EventLog(ResourceOptionArgs),
+    /// Print what the target writes to its console, such as with semihosting
+    Console(ConsoleArgs),
+    /// Run the simulation and count the events of a source, such as
+    /// instructions retired or cache misses
+    Counter(CounterArgs),
     /// Describe the matching registers of an instance
-    RegisterList(InstanceArgs),
+    RegisterList(RegisterListArgs),
+    /// List the groups of registers of an instance, for --group
+    RegisterGroups(InstanceArgs),
     /// Tabulate memory spaces
     MemorySpaces(InstanceArgs),
     /// Tabulate memory sideband info
@@ -38,16 +64,62 @@ enum Command {
     MemoryTranslate(TranslateArgs),
     /// Print the children of this instance
     ChildList(OptionalInstanceArgs),
+    /// Print the tree of instances below this instance
+    Tree(OptionalInstanceArgs),
+    /// Tabulate the functions an instance exposes
+    Functions(FunctionArgs),
+    /// Call any Iris function with JSON parameters and print its result
+    Raw(RawArgs),
     /// Read memory from the prespective of an instance
     MemoryRead(ReadMemArgs),
+    /// Write bytes to memory from the perspective of an instance
+    #[clap(alias = "mem-write")]
+    MemoryWrite(WriteMemArgs),
+    /// Disassemble instructions with the model's own disassembler
+    #[clap(alias = "disas")]
+    Disassemble(DisassembleArgs),
     /// Break at a pc range
-    Break(ReadMemArgs),
+    Break(BreakArgs),
+    /// List the breakpoints set in an instance
+    BreakList(InstanceArgs),
+    /// Run until an access to a range of memory, and report the access
+    Watch(WatchArgs),
+    /// Step an instance and report where it stopped
+    Step(StepArgs),
     /// Reset the platform
     Reset,
     /// Read matching registers from an instance
-    RegisterRead(ResourceReadArgs),
-    /// Provide a GDB server for the iris server over a pipe
-    GdbProxy(InstanceArgs),
+    RegisterRead(RegisterReadArgs),
+    /// Print every register of an instance, sorted by name
+    DumpRegs(InstanceArgs),
+    /// Write a value to a register of an instance
+    #[clap(alias = "reg-write")]
+    RegisterWrite(ResourceWriteArgs),
+    /// Provide a GDB server for the iris server over a pipe or TCP
+    GdbProxy(GdbProxyArgs),
+}
+
+#[derive(Parser, Debug)]
+struct GdbProxyArgs {
+    /// The name of the instance to debug
+    inst: String,
+    /// Listen for gdb on this address instead of using stdin and stdout
+    #[clap(long)]
+    listen: Option<SocketAddr>,
+    /// With --listen, wait for another gdb after each one disconnects
+    #[clap(long, requires = "listen")]
+    reconnect: bool,
+    /// Present registers big-endian, whatever the model reports
+    #[clap(long)]
+    big_endian: bool,
+    /// Set gdb's software breakpoints by writing a trap instruction to
+    /// memory, for models that halt on one
+    #[clap(long)]
+    patch_breakpoints: bool,
+    /// Set breakpoints in every memory space of an AArch64 core, such as
+    /// both secure and non-secure, not only the one the PC is in
+    #[clap(long)]
+    break_all_spaces: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -62,6 +134,23 @@ struct InstanceArgs {
     inst: String,
 }
 
+#[derive(Parser, Debug)]
+struct FunctionArgs {
+    /// The name of the instance to query
+    inst: String,
+    /// Only list functions whose names start with this
+    prefix: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct RawArgs {
+    /// The name of the function, such as instanceRegistry_getList
+    method: String,
+    /// The parameters of the function, as a JSON object
+    #[clap(default_value = "{}")]
+    params: String,
+}
+
 #[derive(Parser, Debug)]
 struct SidebandArgs {
     /// The name of the instance to read from
@@ -97,21 +186,18 @@ impl FromStr for SpaceArg {
 }
 
 impl SpaceArg {
-    fn into_id(self, fvp: &mut FastModelIris, inst: u32) -> Result<u64, std::io::Error> {
+    fn into_id(self, fvp: &mut FastModelIris, inst: u32) -> Result<u64, Box<dyn Error>> {
         let num = u64::from_str(&self.inner);
         if let Ok(n) = num {
             return Ok(n);
         }
-        let spaces = memory::spaces(fvp, inst)?;
+        let spaces = memory::cached_spaces(fvp, inst)?;
         match spaces
             .iter()
             .find(|i| i.name.to_lowercase() == self.inner.to_lowercase())
         {
             Some(spc) => Ok(spc.id),
-            None => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Space {} not found", self.inner),
-            )),
+            None => Err(format!("Space {} not found", self.inner).into()),
         }
     }
 }
@@ -125,9 +211,111 @@ struct ReadMemArgs {
     /// Size of memory block to print in bytes. When not present defaults to
     /// 4 bytes
     size: Option<String>,
-    /// Type of the memory block
+    /// Type of the memory block. Defaults to the --width
     #[clap(short, long)]
     group_by: Option<GroupBy>,
+    /// Read with accesses of this many bytes, 1, 2, 4 or 8, such as for
+    /// memory mapped registers that must be read whole
+    #[clap(long, default_value = "1")]
+    width: u64,
+    /// Read this many elements of --width bytes, in decimal, instead of
+    /// giving a size
+    #[clap(long, conflicts_with = "size")]
+    count: Option<u64>,
+    /// Dim the bytes that are not printable ASCII
+    #[clap(long)]
+    color: bool,
+    /// Ask the model to read without side effects, such as clearing a
+    /// status register that clears when read
+    #[clap(long)]
+    no_side_effects: bool,
+}
+
+#[derive(Parser, Debug)]
+struct WriteMemArgs {
+    /// The name of the instance to write from
+    inst: String,
+    /// Address to write to
+    addr: String,
+    /// Bytes to write in hex, such as `deadbeef` or `de ad be ef`
+    #[clap(required = true)]
+    data: Vec<String>,
+    /// Memory space to write to. When not present defaults to space 0
+    #[clap(short, long)]
+    space: Option<SpaceArg>,
+}
+
+fn parse_hex_bytes(words: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let digits: String = words.iter().flat_map(|w| w.split_whitespace()).collect();
+    if !digits.is_ascii() || digits.len() % 2 != 0 {
+        return Err(format!("{} is not a whole number of hex bytes", digits).into());
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&digits[i..i + 2], 16)?))
+        .collect()
+}
+
+#[derive(Parser, Debug)]
+struct BreakArgs {
+    /// The name of the instance to break in
+    inst: String,
+    /// Address to break at, or with --elf, a symbol
+    addr: String,
+    /// Size of the range to break on in bytes
+    size: Option<String>,
+    /// Give up and stop the simulation after this many seconds
+    #[clap(long, value_name = "SECS")]
+    timeout: Option<f64>,
+    /// ELF file to look up a symbol address in
+    #[cfg(feature = "elf")]
+    #[clap(long)]
+    elf: Option<std::path::PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct WatchArgs {
+    /// The name of the instance to watch accesses from
+    inst: String,
+    /// Address to watch
+    addr: String,
+    /// Size of the range to watch in bytes. When not present defaults to 4
+    /// bytes
+    #[clap(short, long)]
+    size: Option<String>,
+    /// Accesses to stop on, one of r, w or rw
+    #[clap(short, long, default_value = "w")]
+    mode: RwMode,
+    /// Give up and stop the simulation after this many seconds
+    #[clap(long, value_name = "SECS")]
+    timeout: Option<f64>,
+}
+
+#[derive(Parser, Debug)]
+struct StepArgs {
+    /// The name of the instance to step
+    inst: String,
+    /// Number of steps to take
+    count: u64,
+    /// What a step counts, either instruction or cycle
+    #[clap(short, long, default_value = "instruction")]
+    unit: step::Unit,
+    /// Step backwards, on models recording for reverse execution
+    #[clap(long)]
+    reverse: bool,
+}
+
+#[derive(Parser, Debug)]
+struct DisassembleArgs {
+    /// The name of the instance to disassemble for
+    inst: String,
+    /// Address of the first instruction
+    addr: String,
+    /// Number of instructions. When not present defaults to 10
+    count: Option<u64>,
+    /// Disassembly mode, such as an instruction set, if not the current one
+    #[clap(short, long)]
+    mode: Option<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -137,44 +325,64 @@ struct ResourceReadArgs {
     /// Resource to print from
     resource: String,
 }
+
 #[derive(Parser, Debug)]
-struct ResourceOptionArgs {
+struct CounterArgs {
+    /// The name of the instance to count events of
+    inst: String,
+    /// The event source to count
+    source: String,
+    /// Stop the simulation after this many milliseconds, rather than
+    /// waiting for it to stop by itself
+    #[clap(long = "for", value_name = "MS")]
+    millis: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+struct RegisterListArgs {
+    /// The name of the instance to query
+    inst: String,
+    /// Only list the registers in this group, as named by register-groups
+    #[clap(long)]
+    group: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct RegisterReadArgs {
     /// The name of the instance to read from
     inst: String,
     /// Resource to print from
-    resource: Option<String>,
+    resource: String,
+    /// Only read the registers in this group, as named by register-groups
+    #[clap(long)]
+    group: Option<String>,
+}
+#[derive(Parser, Debug)]
+struct ResourceWriteArgs {
+    /// The name of the instance to write to
+    inst: String,
+    /// Exact name of the register to write
+    resource: String,
+    /// Value to write in hex, with or without a leading 0x
+    value: String,
 }
 
-#[allow(unused)]
-fn enable_events(
-    fvp: &mut FastModelIris,
-    my_id: u32,
-    cpus: &[instance_registry::Instance],
-    event_names: &[&str],
-) -> std::io::Result<()> {
-    for cpu in cpus {
-        let sources = event_names
-            .iter()
-            .map(|name| event::Source {
-                id: cpu.id,
-                name: name.to_string(),
-            })
-            .collect::<Vec<_>>();
-        let sources = fvp.batch(&sources)?;
-        let streams = sources
-            .into_iter()
-            .map(|src| event_stream::Create {
-                id: Some(cpu.id),
-                disable: false,
-                to_id: my_id,
-                source: src.id,
-                buffer: false,
-                stop: false,
-            })
-            .collect::<Vec<_>>();
-        fvp.batch(&streams)?;
-    }
-    Ok(())
+#[derive(Parser, Debug)]
+struct ConsoleArgs {
+    /// The name of the instance to listen to
+    inst: String,
+    /// Event source carrying the text, such as a UART's, instead of
+    /// semihosting
+    #[clap(long)]
+    source: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct ResourceOptionArgs {
+    /// The name of the instance to read from
+    inst: String,
+    /// Resource to print from
+    resource: Option<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -199,254 +407,508 @@ impl FromStr for GroupBy {
     }
 }
 
-fn mismatch(xs: &[u8], ys: &[u8]) -> usize {
-    mismatch_chunks::<128>(xs, ys)
+/// Print `buff` as a hex dump. Groups wider than a byte are decoded with
+/// the endianness of the memory space, and with `color` the bytes that are
+/// not printable ASCII are dimmed in the gutter.
+fn print_hex_dump(address: u64, buff: &[u8], group_by: GroupBy, big_endian: bool, color: bool) {
+    let width = match group_by {
+        GroupBy::U8 => 1,
+        GroupBy::U16 => 2,
+        GroupBy::U32 => 4,
+        GroupBy::U64 => 8,
+    };
+    print!(
+        "{}",
+        memory::hex_dump(address, buff, width, big_endian, color)
+    );
 }
 
-fn mismatch_chunks<const N: usize>(xs: &[u8], ys: &[u8]) -> usize {
-    let off = iter::zip(xs.chunks_exact(N), ys.chunks_exact(N))
-        .take_while(|(x, y)| x == y)
-        .count()
-        * N;
-    off + iter::zip(&xs[off..], &ys[off..])
-        .take_while(|(x, y)| x == y)
-        .count()
+/// Deepest a `Tree` goes, in case of a model with very odd names
+const MAX_TREE_DEPTH: usize = 64;
+
+/// The instances below some instance, by the next component of their name
+#[derive(Serialize, Debug, Default)]
+#[serde(transparent)]
+struct InstanceTree(BTreeMap<String, InstanceTree>);
+
+impl InstanceTree {
+    fn insert(&mut self, path: &str) {
+        let mut node = self;
+        for seg in path
+            .split('.')
+            .filter(|s| !s.is_empty())
+            .take(MAX_TREE_DEPTH)
+        {
+            node = node.0.entry(seg.to_string()).or_default();
+        }
+    }
+
+    fn print(&self, indent: &str) {
+        let mut children = self.0.iter().peekable();
+        while let Some((name, child)) = children.next() {
+            let last = children.peek().is_none();
+            println!("{}{}{}", indent, if last { "└─ " } else { "├─ " }, name);
+            child.print(&format!("{}{}", indent, if last { "   " } else { "│  " }));
+        }
+    }
 }
 
-fn common_prefix_len<'a, I: IntoIterator<Item = &'a str>>(haystack: I) -> usize {
-    let mut haystack = haystack.into_iter();
-    let start = match haystack.next() {
-        Some(start) => start,
-        None => return 0,
-    };
-    let prefix = |e: &str| mismatch(e.as_bytes(), start.as_bytes());
-    haystack.map(prefix).min().unwrap_or(0)
+fn print_json<T: Serialize>(value: &T) -> Result<(), Box<dyn Error>> {
+    serde_json::to_writer_pretty(stdout(), value)?;
+    println!();
+    Ok(())
 }
 
-fn find_instance(
-    fvp: &mut FastModelIris,
+#[derive(Serialize, Debug)]
+struct RegisterValue {
     name: String,
-) -> Result<instance_registry::Instance, std::io::Error> {
-    if let Ok(inst) = instance_registry::get_instance_by_name(fvp, name.clone()) {
-        return Ok(inst);
-    }
-    let name = &name.trim_start_matches(".");
-    let instance_list = instance_registry::list_instances(fvp, "component".to_string())?;
-    let prefix = common_prefix_len(instance_list.iter().map(|i| i.name.as_str()));
-    for inst in instance_list {
-        let n = &inst.name[prefix..].trim_start_matches(".");
-        if n == name {
-            return Ok(inst);
+    value: RegisterData,
+}
+
+/// A register's value, or a string parameter's
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum RegisterData {
+    Number(u64),
+    Text(String),
+}
+
+impl fmt::Display for RegisterData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterData::Number(value) => fmt::LowerHex::fmt(value, f),
+            RegisterData::Text(text) => text.fmt(f),
         }
     }
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "Instance not found",
-    ))
-}
-
-fn print_hex_dump(address: u64, buff: &[u8], group_by: GroupBy) {
-    match group_by {
-        GroupBy::U8 => println!("         0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f"),
-        GroupBy::U16 => println!("         0    2    4    6    8    a    c    e"),
-        GroupBy::U32 => println!("         0        4        8        c"),
-        GroupBy::U64 => println!("         0                8"),
+}
+
+fn print_register_values(values: &[RegisterValue], json: bool) -> Result<(), Box<dyn Error>> {
+    if json {
+        return print_json(&values);
     }
-    let addr_range = (address as usize)..(address as usize + buff.len());
-    let base = (address & !0xf) as usize;
-    let step = match group_by {
-        GroupBy::U8 => 1,
-        GroupBy::U16 => 2,
-        GroupBy::U32 => 4,
-        GroupBy::U64 => 8,
-    };
-    for base_addr in (base..base + buff.len()).step_by(0x10) {
-        print!("{:08x}", base_addr);
-        for cur_addr in (base_addr..base_addr + 0x10).step_by(step) {
-            if addr_range.contains(&cur_addr) {
-                let offset = cur_addr - address as usize;
-                let slice = &buff[offset..offset + step];
-                match group_by {
-                    GroupBy::U8 => print!(" {:02x}", buff[offset]),
-                    GroupBy::U16 => {
-                        print!(" {:04x}", u16::from_le_bytes(slice.try_into().unwrap()))
-                    }
-                    GroupBy::U32 => {
-                        print!(" {:08x}", u32::from_le_bytes(slice.try_into().unwrap()))
-                    }
-                    GroupBy::U64 => {
-                        print!(" {:016x}", u64::from_le_bytes(slice.try_into().unwrap()))
-                    }
-                }
-            } else {
-                print!(" {:width$}", "", width = step * 2);
-            }
-        }
-        print!(" ");
-        for cur_addr in base_addr..base_addr + 0x10 {
-            if addr_range.contains(&cur_addr) {
-                let byte = buff[cur_addr - address as usize];
-                if byte.is_ascii_graphic() {
-                    print!("{}", char::from(byte));
-                } else {
-                    print!(".");
-                }
-            } else {
-                print!(" ");
-            }
+    println!("{:>8} │ name", "value");
+    println!("{:═>8}═╪═{:═<35}", "", "");
+    for RegisterValue { name, value } in values {
+        println!("{:>8} │ {}", value, name);
+    }
+    Ok(())
+}
+
+/// Prints the warnings and errors that cornea logs
+#[cfg(feature = "log")]
+struct StderrLogger;
+
+#[cfg(feature = "log")]
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
         }
-        println!("");
     }
+
+    fn flush(&self) {}
 }
 
-fn get_iris(port: Option<u16>) -> Result<FastModelIris, std::io::Error> {
+fn get_iris(host: &str, port: Option<u16>) -> Result<FastModelIris, CorneaError> {
     if let Some(port) = port {
-        FastModelIris::from_port(None, port)
+        FastModelIris::from_addr_retry(None, (host, port), 5, Duration::from_millis(200))
     } else {
-        let mut fvp = FastModelIris::from_port(None, 7100);
+        let mut fvp = FastModelIris::from_addr(None, (host, 7100));
         for port in 7101..7105 {
             if fvp.is_ok() {
                 break;
             }
-            fvp = FastModelIris::from_port(None, port)
+            fvp = FastModelIris::from_addr(None, (host, port))
         }
         fvp
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn serve_gdb<T: Target<Error = ()>>(
+    proxy: &mut T,
+    listen: Option<SocketAddr>,
+    reconnect: bool,
+) -> Result<(), Box<dyn Error>> {
+    let addr = match listen {
+        Some(addr) => addr,
+        None => {
+            let mut stub = GdbStub::new(GdbOverPipe::new(stdin(), stdout()));
+            eprintln!("Disconnected with {:?}", stub.run(proxy)?);
+            return Ok(());
+        }
+    };
+    let listener = TcpListener::bind(addr)?;
+    loop {
+        eprintln!("Waiting for gdb on {}", listener.local_addr()?);
+        let mut stub = GdbStub::new(GdbOverTcp::accept(&listener)?);
+        match stub.run(proxy) {
+            Ok(reason) => eprintln!("Disconnected with {:?}", reason),
+            // A gdb that goes away without detaching is only an error when
+            // there is no other to wait for
+            Err(e) if reconnect => eprintln!("Disconnected with {}", e),
+            Err(e) => return Err(e.into()),
+        }
+        if !reconnect {
+            return Ok(());
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     let args = Cli::parse();
-    let mut fvp = get_iris(args.port)?;
+    #[cfg(feature = "log")]
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(log::LevelFilter::Warn);
+    }
+    let mut fvp = get_iris(&args.host, args.port)?;
     let my_id = fvp.register()?;
+    let json = args.json;
+    let engine = args.engine;
     use Command::*;
     match args.command {
-        RegisterList(InstanceArgs { inst }) => {
-            let instance = find_instance(&mut fvp, inst)?;
-            println!(
-                "{:<6}│{:^6}│ {:>20} │ {}",
-                "type", "bits", "name", "description"
-            );
-            println!("{:═<6}╪{:═^6}╪═{:═>20}═╪═{:═<20}", "", "", "", "");
-            for res in resource::get_list(&mut fvp, instance.id, None, None)? {
-                let typ = if res.parameter_info.is_none() {
-                    "Reg"
-                } else {
-                    "Param"
-                };
-                let name = res.name;
-                let bits = res.bit_width;
-                let description = res.description.unwrap_or_else(|| "".to_string());
-                println!("{typ:<6}│{bits:>5} │ {name:>20} │ {description}");
+        RegisterList(RegisterListArgs { inst, group }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let resources = resource::get_list(&mut fvp, instance.id, group, None)?;
+            if json {
+                print_json(&resources)?;
+            } else {
+                println!("{:<6}│{:^6}│ {:>20} │ description", "type", "bits", "name");
+                println!("{:═<6}╪{:═^6}╪═{:═>20}═╪═{:═<20}", "", "", "", "");
+                for res in resources {
+                    let typ = if res.parameter_info.is_none() {
+                        "Reg"
+                    } else {
+                        "Param"
+                    };
+                    let name = res.name;
+                    let bits = res.bit_width;
+                    let mut description = res.description.unwrap_or_else(|| "".to_string());
+                    if let Some(param) = res.parameter_info {
+                        if let Some(default) = param.default_value.as_ref().and_then(|d| d.first())
+                        {
+                            description.push_str(&format!(" (default {default:#x})"));
+                        } else if let Some(default) = param.default_string {
+                            description.push_str(&format!(" (default {default:?})"));
+                        }
+                        if !param.enums.is_empty() {
+                            let symbols: Vec<_> =
+                                param.enums.iter().map(|e| e.symbol.as_str()).collect();
+                            description.push_str(&format!(" [{}]", symbols.join(", ")));
+                        }
+                    }
+                    println!("{typ:<6}│{bits:>5} │ {name:>20} │ {description}");
+                }
+            }
+        }
+        Functions(FunctionArgs { inst, prefix }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let functions: BTreeMap<_, _> = instance_registry::get_function_info(
+                &mut fvp,
+                instance.id,
+                prefix.unwrap_or_default(),
+            )?
+            .into_iter()
+            .collect();
+            if json {
+                print_json(&functions)?;
+            } else {
+                let name_len = functions.keys().map(|n| n.len()).max().unwrap_or(0);
+                println!("{:>name_len$} │ arguments", "name");
+                println!("{:═>name_len$}═╪═{:═<20}", "", "");
+                for (name, info) in functions {
+                    let mut args: Vec<_> = info
+                        .args
+                        .into_iter()
+                        .map(|(arg, attr)| {
+                            let optional = if attr.optional.unwrap_or(false) {
+                                "?"
+                            } else {
+                                ""
+                            };
+                            format!("{arg}{optional}: {}", attr.typ)
+                        })
+                        .collect();
+                    args.sort();
+                    println!("{name:>name_len$} │ {}", args.join(", "));
+                    if !info.description.is_empty() {
+                        println!("{:>name_len$} │   {}", "", info.description);
+                    }
+                }
             }
         }
+        Raw(RawArgs { method, params }) => {
+            let params = serde_json::from_str(&params)?;
+            print_json(&fvp.execute_raw(&method, params)?)?;
+        }
         EventSources(InstanceArgs { inst }) => {
-            let instance = find_instance(&mut fvp, inst)?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
             let sources = event::sources(&mut fvp, instance.id)?;
-            let name_len = sources.iter().map(|s| s.name.len()).max().unwrap_or(0);
-            println!("{:>name_len$} │ {}", "name", "description");
-            println!("{:═>name_len$}═╪═{:═<20}", "", "");
-            for res in sources {
-                let name = res.name;
-                let description = res.description.unwrap_or_else(|| "".to_string());
-                println!("{name:>name_len$} │ {description}");
+            if json {
+                print_json(&sources)?;
+            } else {
+                let name_len = sources.iter().map(|s| s.name.len()).max().unwrap_or(0);
+                println!("{:>name_len$} │ description", "name");
+                println!("{:═>name_len$}═╪═{:═<20}", "", "");
+                for res in sources {
+                    let name = res.name;
+                    let description = res.description.unwrap_or_else(|| "".to_string());
+                    println!("{name:>name_len$} │ {description}");
+                }
             }
         }
         EventFields(ResourceReadArgs { inst, resource }) => {
-            let instance = find_instance(&mut fvp, inst)?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
             let source = event::source(&mut fvp, instance.id, resource)?;
-            println!(
-                "{:<6}│{:^6}│ {:>20} │ {}",
-                "type", "size", "name", "description"
-            );
-            println!("{:═<6}╪{:═^6}╪═{:═>20}═╪═{:═<20}", "", "", "", "");
-            for res in source.fields {
-                let typ = res.typ;
-                let name = res.name;
-                let bits = res.size;
-                let description = res.description.unwrap_or_else(|| "".to_string());
-                println!("{typ:<6}│{bits:>5} │ {name:>20} │ {description}");
+            if json {
+                print_json(&source)?;
+            } else {
+                println!("{:<6}│{:^6}│ {:>20} │ description", "type", "size", "name");
+                println!("{:═<6}╪{:═^6}╪═{:═>20}═╪═{:═<20}", "", "", "", "");
+                for res in source.fields {
+                    let typ = res.typ;
+                    let name = res.name;
+                    let bits = res.size;
+                    let description = res.description.unwrap_or_else(|| "".to_string());
+                    println!("{typ:<6}│{bits:>5} │ {name:>20} │ {description}");
+                }
             }
         }
         EventLog(ResourceOptionArgs {
             inst,
             resource: Some(resource),
         }) => {
-            let instance = find_instance(&mut fvp, inst)?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
             let source = event::source(&mut fvp, instance.id, resource.clone())?;
-            let _stream = event_stream::create(
-                &mut fvp,
-                Some(instance.id),
-                false,
-                my_id,
-                source.id,
-                false,
-                false,
-            )?;
+            let _stream = event_stream::subscribe(&mut fvp, Some(instance.id), my_id, source.id)?;
             fvp.register_callback(
                 format!("ec_{}", resource),
-                Box::new(|params| Ok(println!("{}", params))),
+                Box::new(|params| {
+                    // Stop quietly when whatever reads the log goes away,
+                    // such as `head`
+                    match writeln!(stdout(), "{}", params) {
+                        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(CallbackFlow::Stop),
+                        res => Ok(res.map(|()| CallbackFlow::Keep)?),
+                    }
+                }),
             );
-            fvp.wait_for_events();
+            fvp.run_event_loop()?;
         }
         EventLog(ResourceOptionArgs {
             inst,
             resource: None,
         }) => {
-            let instance = find_instance(&mut fvp, inst)?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
             let sources = event::sources(&mut fvp, instance.id)?;
             for s in sources {
-                let _stream = event_stream::create(
-                    &mut fvp,
-                    Some(instance.id),
-                    false,
-                    my_id,
-                    s.id,
-                    false,
-                    false,
-                );
+                let _stream = event_stream::subscribe(&mut fvp, Some(instance.id), my_id, s.id);
             }
-            fvp.wait_for_events();
-        }
-        RegisterRead(ResourceReadArgs { inst, resource }) => {
-            let instance = find_instance(&mut fvp, inst)?;
-            println!("{:>8} │ {}", "value", "name");
-            println!("{:═>8}═╪═{:═<35}", "", "");
-            for res in resource::get_list(&mut fvp, instance.id, None, None)? {
-                if res.name.starts_with(&resource) {
-                    let val = resource::read(&mut fvp, instance.id, vec![res.id])?;
-                    if !val.data.is_empty() {
-                        println!("{:>8x} │ {}", val.data[0], res.name);
+            fvp.run_event_loop()?;
+        }
+        Console(ConsoleArgs { inst, source }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let source = match source {
+                Some(name) => event::source(&mut fvp, instance.id, name)?,
+                None => console::source(&mut fvp, instance.id)?.ok_or_else(|| {
+                    format!(
+                        "{} has no console event source, pick one with --source",
+                        instance.name
+                    )
+                })?,
+            };
+            let _stream = event_stream::subscribe(&mut fvp, Some(instance.id), my_id, source.id)?;
+            fvp.register_callback(
+                format!("ec_{}", source.name),
+                Box::new(|params| {
+                    if let Some(text) = console::decode_text(&params) {
+                        let mut out = stdout();
+                        out.write_all(text.as_bytes())?;
+                        out.flush()?;
                     }
+                    Ok(CallbackFlow::Keep)
+                }),
+            );
+            fvp.run_event_loop()?;
+        }
+        Counter(CounterArgs {
+            inst,
+            source,
+            millis,
+        }) => {
+            let sim = instance_registry::simulation_engine(&mut fvp, engine.as_deref())?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let source = event::source(&mut fvp, instance.id, source)?;
+            let stream = event_stream::create_counter(&mut fvp, instance.id, my_id, source.id)?;
+            simulation_time::run(&mut fvp, sim.id)?;
+            if let Some(millis) = millis {
+                std::thread::sleep(Duration::from_millis(millis));
+                simulation_time::stop(&mut fvp, sim.id)?;
+            }
+            while simulation_time::get(&mut fvp, sim.id)?.running {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let count = event_stream::counter(&mut fvp, instance.id, stream)?;
+            event_stream::destroy(&mut fvp, instance.id, stream)?;
+            if json {
+                print_json(&count)?;
+            } else {
+                println!("{} {}", count, source.name);
+            }
+        }
+        RegisterGroups(InstanceArgs { inst }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let groups = resource::groups(&mut fvp, instance.id)?;
+            if json {
+                print_json(&groups)?;
+            } else {
+                let name_len = groups.iter().map(|g| g.name.len()).max().unwrap_or(0);
+                println!("{:>name_len$} │ count │ description", "name");
+                println!("{:═>name_len$}═╪═══════╪═{:═<35}", "", "");
+                for group in &groups {
+                    println!(
+                        "{:>name_len$} │ {:>5} │ {}",
+                        group.name,
+                        group.ids.len(),
+                        group.description.as_deref().unwrap_or("")
+                    );
                 }
             }
         }
+        RegisterRead(RegisterReadArgs {
+            inst,
+            resource,
+            group,
+        }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let resources = match group {
+                Some(group) => resource::get_list(&mut fvp, instance.id, Some(group), None)?,
+                None => resource::cached_list(&mut fvp, instance.id)?,
+            };
+            let wanted: Vec<_> = resources
+                .into_iter()
+                .filter(|res| res.name.starts_with(&resource))
+                .collect();
+            let read = resource::read_values(&mut fvp, instance.id, &wanted)?;
+            let mut values = Vec::new();
+            for (res, value) in wanted.into_iter().zip(read) {
+                let value = match value {
+                    ResourceValue::Numeric(words) => match words.first() {
+                        Some(&word) => RegisterData::Number(word),
+                        None => continue,
+                    },
+                    ResourceValue::Str(text) => RegisterData::Text(text),
+                };
+                values.push(RegisterValue {
+                    name: res.name,
+                    value,
+                });
+            }
+            print_register_values(&values, json)?;
+        }
+        DumpRegs(InstanceArgs { inst }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let values: Vec<_> = resource::dump_registers(&mut fvp, instance.id)?
+                .into_iter()
+                .map(|(name, value)| RegisterValue {
+                    name,
+                    value: RegisterData::Number(value),
+                })
+                .collect();
+            print_register_values(&values, json)?;
+        }
+        RegisterWrite(ResourceWriteArgs {
+            inst,
+            resource,
+            value,
+        }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let value = u64::from_str_radix(value.trim_start_matches("0x"), 16)?;
+            let written = resource::write_named(&mut fvp, instance.id, &resource, value)?;
+            if let Some(error) = written.error {
+                return Err(format!("Could not write {}: {}", resource, error).into());
+            }
+            let val = resource::read_named(&mut fvp, instance.id, &[&resource])?;
+            let values: Vec<_> = val
+                .get(&resource)
+                .and_then(|words| words.first())
+                .map(|&value| RegisterValue {
+                    name: resource.clone(),
+                    value: RegisterData::Number(value),
+                })
+                .into_iter()
+                .collect();
+            print_register_values(&values, json)?;
+        }
         ChildList(OptionalInstanceArgs { inst }) => {
             let name = match inst.clone() {
-                Some(i) => find_instance(&mut fvp, i)?.name,
+                Some(i) => instance_registry::resolve(&mut fvp, &i)?.name,
                 None => String::new(),
             };
-            for instance in instance_registry::list_instances(&mut fvp, name.clone())? {
-                if instance.name != name {
+            let mut children = instance_registry::list_instances(&mut fvp, name.clone())?;
+            children.retain(|instance| instance.name != name);
+            if json {
+                print_json(&children)?;
+            } else {
+                for instance in children {
                     println!("{}", instance.name.trim_start_matches(&name));
                 }
             }
         }
+        Tree(OptionalInstanceArgs { inst }) => {
+            let instances = instance_registry::list_instances(&mut fvp, String::new())?;
+            let (root, prefix) = match inst {
+                Some(i) => {
+                    let name = instance_registry::resolve(&mut fvp, &i)?.name;
+                    let prefix = name.len();
+                    (name, prefix)
+                }
+                None => {
+                    let names = instances.iter().map(|i| i.name.as_str());
+                    (String::new(), instance_registry::common_prefix_len(names))
+                }
+            };
+            let mut tree = InstanceTree::default();
+            for instance in &instances {
+                let below = instance
+                    .name
+                    .strip_prefix(&root)
+                    .is_some_and(|rest| rest.starts_with('.'));
+                if root.is_empty() || below {
+                    tree.insert(&instance.name[prefix..]);
+                }
+            }
+            if json {
+                print_json(&tree)?;
+            } else {
+                if !root.is_empty() {
+                    println!("{}", root);
+                }
+                tree.print("");
+            }
+        }
         MemoryInfo(SidebandArgs { inst, addr }) => {
-            let instance = find_instance(&mut fvp, inst)?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
             let addr = u64::from_str_radix(&addr, 16)?;
             let info = memory::sideband_info(&mut fvp, instance.id, 0, addr)?;
-            println!(
-                "{:>8} │ {:>8} │ {:>8} │ {:>8} │ {:>2}",
-                "Start", "End addr", "Phys", "IPA", "NX"
-            );
-            println!(
-                "{:>8x} │ {:>8x} │ {:>8x} │ {:>8x} │ {:>2}",
-                info.region_start,
-                info.region_end,
-                info.physical_address,
-                info.ipa,
-                if info.no_execute { "Y" } else { "" }
-            );
+            if json {
+                print_json(&info)?;
+            } else {
+                println!(
+                    "{:>8} │ {:>8} │ {:>8} │ {:>8} │ {:>2}",
+                    "Start", "End addr", "Phys", "IPA", "NX"
+                );
+                println!(
+                    "{:>8x} │ {:>8x} │ {:>8x} │ {:>8x} │ {:>2}",
+                    info.region_start,
+                    info.region_end,
+                    info.physical_address,
+                    info.ipa,
+                    if info.no_execute { "Y" } else { "" }
+                );
+            }
         }
         MemoryTranslate(TranslateArgs {
             inst,
@@ -454,27 +916,38 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             from,
             to,
         }) => {
-            let instance = find_instance(&mut fvp, inst)?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
             let addr = u64::from_str_radix(&addr, 16)?;
             let from = from.into_id(&mut fvp, instance.id)?;
             let to = to.into_id(&mut fvp, instance.id)?;
             let out_addr = memory::translate(&mut fvp, instance.id, addr, from, to)?.address;
-            for oa in out_addr {
-                println!("{oa:>8x}");
+            if json {
+                print_json(&out_addr)?;
+            } else {
+                if out_addr.is_empty() {
+                    println!("{addr:x} is not mapped");
+                }
+                for oa in out_addr {
+                    println!("{oa:>8x}");
+                }
             }
         }
         MemorySpaces(InstanceArgs { inst }) => {
-            let instance = find_instance(&mut fvp, inst)?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
             let spaces = memory::spaces(&mut fvp, instance.id)?;
-            let name_len = spaces.iter().map(|s| s.name.len()).max().unwrap_or(0);
-            println!("{:>name_len$} │ {}", "name", "description");
-            println!("{:═>name_len$}═╪═{:═<35}", "", "");
-            for space in &spaces {
-                println!(
-                    "{:>name_len$} │ {}",
-                    space.name,
-                    space.description.as_deref().unwrap_or("")
-                );
+            if json {
+                print_json(&spaces)?;
+            } else {
+                let name_len = spaces.iter().map(|s| s.name.len()).max().unwrap_or(0);
+                println!("{:>name_len$} │ description", "name");
+                println!("{:═>name_len$}═╪═{:═<35}", "", "");
+                for space in &spaces {
+                    println!(
+                        "{:>name_len$} │ {}",
+                        space.name,
+                        space.description.as_deref().unwrap_or("")
+                    );
+                }
             }
         }
         MemoryRead(ReadMemArgs {
@@ -482,57 +955,330 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             addr,
             size,
             group_by,
+            width,
+            count,
+            color,
+            no_side_effects,
         }) => {
-            let instance = find_instance(&mut fvp, inst)?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
             let addr = u64::from_str_radix(&addr, 16)?;
-            let size = u64::from_str_radix(&size.unwrap_or_else(|| "4".to_string()), 16)?;
-            let memory = memory::read(&mut fvp, instance.id, 0, addr, 1, size)?;
-            let buf: Vec<_> = memory
-                .data
-                .into_iter()
-                .map(|u| u.to_le_bytes())
-                .flatten()
-                .collect();
-            print_hex_dump(addr, &buf, group_by.unwrap_or(GroupBy::U8));
+            let element = match width {
+                1 => GroupBy::U8,
+                2 => GroupBy::U16,
+                4 => GroupBy::U32,
+                8 => GroupBy::U64,
+                _ => return Err("--width must be 1, 2, 4 or 8".into()),
+            };
+            let count = match count {
+                Some(count) => count,
+                None => {
+                    let size = u64::from_str_radix(&size.unwrap_or_else(|| "4".to_string()), 16)?;
+                    if size % width != 0 {
+                        return Err("The size must be a multiple of --width".into());
+                    }
+                    size / width
+                }
+            };
+            memory::check_bounds(&mut fvp, instance.id, 0, addr, count.saturating_mul(width))?;
+            let buf = if no_side_effects {
+                memory::peek_elements(&mut fvp, instance.id, 0, addr, width, count)?
+            } else {
+                memory::read_elements(&mut fvp, instance.id, 0, addr, width, count)?
+            };
+            // Wider reads return values, so their bytes are little endian
+            let big_endian = width == 1 && memory::is_big_endian(&mut fvp, instance.id, 0)?;
+            print_hex_dump(addr, &buf, group_by.unwrap_or(element), big_endian, color);
+        }
+        MemoryWrite(WriteMemArgs {
+            inst,
+            addr,
+            data,
+            space,
+        }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let addr = u64::from_str_radix(&addr, 16)?;
+            let data = parse_hex_bytes(&data)?;
+            let space = match space {
+                Some(space) => space.into_id(&mut fvp, instance.id)?,
+                None => 0,
+            };
+            memory::write_bytes(&mut fvp, instance.id, space, addr, &data)?;
+            let buf = memory::read_bytes(&mut fvp, instance.id, space, addr, data.len() as u64)?;
+            print_hex_dump(addr, &buf, GroupBy::U8, false, false);
         }
-        Break(ReadMemArgs {
-            inst, addr, size, ..
+        Break(BreakArgs {
+            inst,
+            addr,
+            size,
+            timeout,
+            #[cfg(feature = "elf")]
+            elf,
         }) => {
-            let sim = instance_registry::get_instance_by_name(
-                &mut fvp,
-                "framework.SimulationEngine".to_string(),
-            )?;
+            let sim = instance_registry::simulation_engine(&mut fvp, engine.as_deref())?;
             let instance = instance_registry::get_instance_by_name(&mut fvp, inst.clone())?;
-            let addr = u64::from_str_radix(&addr, 16)?;
+            #[cfg(feature = "elf")]
+            let elf = elf.map(cornea::elf::Elf::open).transpose()?;
+            #[cfg(feature = "elf")]
+            let sym = elf.as_ref().and_then(|elf| elf.symbol(&addr));
+            #[cfg(not(feature = "elf"))]
+            let sym = None;
+            let addr = match sym {
+                Some(sym) => sym,
+                None => u64::from_str_radix(&addr, 16)
+                    .map_err(|_| format!("{} is neither an address nor a known symbol", addr))?,
+            };
             let size = size.and_then(|s| u64::from_str_radix(&s, 16).ok());
             let bp = breakpoint::code(&mut fvp, instance.id, addr, size, 0, false)?;
+            let timeout = timeout.map(Duration::try_from_secs_f64).transpose()?;
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let mut reached = true;
             simulation_time::run(&mut fvp, sim.id)?;
-            while simulation_time::get(&mut fvp, sim.id)?.running {}
+            while simulation_time::get(&mut fvp, sim.id)?.running {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    simulation_time::stop(&mut fvp, sim.id)?;
+                    reached = false;
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
             breakpoint::delete(&mut fvp, instance.id, bp)?;
+            if !reached {
+                return Err(format!("Breakpoint at {:x} not reached", addr).into());
+            }
+            #[cfg(feature = "elf")]
+            if let Some(elf) = elf {
+                let pc = resource::read_named(&mut fvp, instance.id, &["PC"])?
+                    .get("PC")
+                    .and_then(|words| words.first())
+                    .copied()
+                    .ok_or("Instance has no PC")?;
+                let loc = elf.locate(pc)?;
+                print!("Stopped at {:x}", pc);
+                if let Some(function) = loc.function {
+                    print!(" in {}", function);
+                }
+                if let (Some(file), Some(line)) = (loc.file, loc.line) {
+                    print!(" at {}:{}", file, line);
+                }
+                println!();
+            }
         }
-        Reset => {
-            let sim = instance_registry::get_instance_by_name(
+        Watch(WatchArgs {
+            inst,
+            addr,
+            size,
+            mode,
+            timeout,
+        }) => {
+            let sim = instance_registry::simulation_engine(&mut fvp, engine.as_deref())?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let addr = u64::from_str_radix(&addr, 16)?;
+            let size = u64::from_str_radix(&size.unwrap_or_else(|| "4".to_string()), 16)?;
+            let source = event::source(&mut fvp, instance.id, "IRIS_BREAKPOINT_HIT".to_string())?;
+            let stream = event_stream::subscribe(&mut fvp, Some(instance.id), my_id, source.id)?;
+            let bp = breakpoint::set(
                 &mut fvp,
-                "framework.SimulationEngine".to_string(),
+                instance.id,
+                addr,
+                Some(mode),
+                Some(size),
+                Some(0),
+                breakpoint::Type::Data,
+                false,
+                false,
             )?;
-            simulation::reset(&mut fvp, sim.id, false)?;
-            simulation::wait(&mut fvp, sim.id)?;
+            let trigger = Rc::new(RefCell::new(None));
+            let last = trigger.clone();
+            fvp.register_callback(
+                "ec_IRIS_BREAKPOINT_HIT".to_string(),
+                Box::new(move |params| {
+                    // Breakpoints that someone else set on the instance
+                    // hit too
+                    match breakpoint::decode_hit(params) {
+                        Ok(hit) if hit.id == bp => *last.borrow_mut() = Some(hit),
+                        _ => (),
+                    }
+                    Ok(CallbackFlow::Keep)
+                }),
+            );
+            let timeout = timeout.map(Duration::try_from_secs_f64).transpose()?;
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let mut reached = true;
+            simulation_time::run(&mut fvp, sim.id)?;
+            loop {
+                // Wait for the hit, and only ask whether the simulation
+                // stopped once it came or nothing arrived for a while
+                let hit = trigger.borrow().is_some();
+                if !hit && fvp.wait_for_event(Duration::from_millis(10))? {
+                    continue;
+                }
+                if !simulation_time::get(&mut fvp, sim.id)?.running {
+                    break;
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    simulation_time::stop(&mut fvp, sim.id)?;
+                    reached = false;
+                    break;
+                }
+                if hit {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+            fvp.poll_events()?;
+            breakpoint::delete(&mut fvp, instance.id, bp)?;
+            event_stream::destroy(&mut fvp, instance.id, stream)?;
+            if !reached {
+                return Err(format!("Watchpoint at {:x} not reached", addr).into());
+            }
+            let hit = trigger.borrow_mut().take();
+            match hit.as_ref().and_then(|hit| Some((hit.access()?, hit.size))) {
+                Some(((kind, at), size)) => {
+                    let kind = match kind {
+                        RwMode::Read => "Read",
+                        RwMode::Write => "Write",
+                        RwMode::ReadWrite => "Access",
+                    };
+                    match size {
+                        Some(size) => println!("{} of {} bytes at {:x}", kind, size, at),
+                        None => println!("{} at {:x}", kind, at),
+                    }
+                }
+                None => println!("Stopped before an access to {:x}", addr),
+            }
+        }
+        Disassemble(DisassembleArgs {
+            inst,
+            addr,
+            count,
+            mode,
+        }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let addr = u64::from_str_radix(&addr, 16)?;
+            let count = count.unwrap_or(10);
+            let lines = disassembler::disassembly(&mut fvp, instance.id, mode, None, addr, count)?;
+            if json {
+                print_json(&lines)?;
+            } else {
+                for line in lines {
+                    println!(
+                        "{:>16x} │ {:<16} │ {}",
+                        line.address, line.opcode, line.disassembly
+                    );
+                }
+            }
+        }
+        BreakList(InstanceArgs { inst }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let breakpoints = breakpoint::list(&mut fvp, instance.id)?;
+            if json {
+                print_json(&breakpoints)?;
+            } else {
+                println!(
+                    "{:>4} │ {:<8} │ {:>16} │ {:>4} │ {:>5} │ on",
+                    "id", "type", "address", "size", "space"
+                );
+                println!(
+                    "{:═>4}═╪═{:═<8}═╪═{:═>16}═╪═{:═>4}═╪═{:═>5}═╪═{:═<3}",
+                    "", "", "", "", "", ""
+                );
+                for bp in breakpoints {
+                    let id = bp.id;
+                    let typ = format!("{:?}", bp.typ);
+                    let addr = bp.address.map(|a| format!("{a:x}")).unwrap_or_default();
+                    let size = bp.size.map(|s| format!("{s:x}")).unwrap_or_default();
+                    let space = bp.space_id.map(|s| s.to_string()).unwrap_or_default();
+                    let on = if bp.enabled { "Y" } else { "" };
+                    println!("{id:>4} │ {typ:<8} │ {addr:>16} │ {size:>4} │ {space:>5} │ {on}");
+                }
+            }
+        }
+        Step(StepArgs {
+            inst,
+            count,
+            unit,
+            reverse,
+        }) => {
+            let sim = instance_registry::simulation_engine(&mut fvp, engine.as_deref())?;
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            if reverse && !simulation_time::supports_reverse(&mut fvp, sim.id)? {
+                return Err("This model cannot run backwards".into());
+            }
+            step::setup(&mut fvp, instance.id, count, unit)?;
+            if reverse {
+                simulation_time::run_reverse(&mut fvp, sim.id)?;
+            } else {
+                simulation_time::run(&mut fvp, sim.id)?;
+            }
+            while simulation_time::get(&mut fvp, sim.id)?.running {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let remaining = step::remaining(&mut fvp, instance.id, unit)?;
+            let pc = resource::read_named(&mut fvp, instance.id, &["PC"])?
+                .get("PC")
+                .and_then(|words| words.first())
+                .copied()
+                .ok_or("Instance has no PC")?;
+            let mut values = vec![RegisterValue {
+                name: "PC".to_string(),
+                value: RegisterData::Number(pc),
+            }];
+            values.push(RegisterValue {
+                name: "remaining steps".to_string(),
+                value: RegisterData::Number(remaining),
+            });
+            print_register_values(&values, json)?;
         }
-        GdbProxy(InstanceArgs { inst }) => {
-            let instance = find_instance(&mut fvp, inst)?;
+        Reset => {
+            let sim = instance_registry::simulation_engine(&mut fvp, engine.as_deref())?;
+            fvp.reset_and_resync(sim.id)?;
+        }
+        GdbProxy(GdbProxyArgs {
+            inst,
+            listen,
+            reconnect,
+            big_endian,
+            patch_breakpoints,
+            break_all_spaces,
+        }) => {
+            let instance = instance_registry::resolve(&mut fvp, &inst)?;
+            let sim = instance_registry::simulation_engine(&mut fvp, engine.as_deref())?;
             let res = resource::get_list(&mut fvp, instance.id, None, None)?;
-            if res.iter().any(|r| r.name == "X30") {
-                use cornea::gdb::a64::{GdbOverPipe, IrisGdbStub};
+            if cornea::gdb::riscv::is_riscv(&res) {
+                use cornea::gdb::riscv::IrisGdbStub;
 
-                let mut proxy = IrisGdbStub::from_instance(&mut fvp, instance.id)?;
-                let mut stub = GdbStub::new(GdbOverPipe::new(stdin(), stdout()));
-                eprintln!("Disconnected with {:?}", stub.run(&mut proxy)?);
+                if big_endian || break_all_spaces {
+                    return Err("--big-endian and --break-all-spaces are not supported \
+                                for RISC-V cores"
+                        .into());
+                }
+                let mut proxy =
+                    IrisGdbStub::from_instance_and_engine(&mut fvp, instance.id, sim.id)?;
+                proxy.set_patch_breakpoints(patch_breakpoints);
+                serve_gdb(&mut proxy, listen, reconnect)?;
+            } else if res.iter().any(|r| r.name == "X30") {
+                use cornea::gdb::a64::IrisGdbStub;
+
+                let mut proxy =
+                    IrisGdbStub::from_instance_and_engine(&mut fvp, instance.id, sim.id)?;
+                proxy.set_patch_breakpoints(patch_breakpoints);
+                proxy.set_break_all_spaces(break_all_spaces);
+                if big_endian {
+                    proxy.set_big_endian(true);
+                }
+                serve_gdb(&mut proxy, listen, reconnect)?;
             } else {
-                use cornea::gdb::t32::{GdbOverPipe, IrisGdbStub};
+                use cornea::gdb::t32::IrisGdbStub;
 
-                let mut proxy = IrisGdbStub::from_instance(&mut fvp, instance.id)?;
-                let mut stub = GdbStub::new(GdbOverPipe::new(stdin(), stdout()));
-                eprintln!("Disconnected with {:?}", stub.run(&mut proxy)?);
+                if break_all_spaces {
+                    return Err("--break-all-spaces is only for AArch64 cores".into());
+                }
+                let mut proxy =
+                    IrisGdbStub::from_instance_and_engine(&mut fvp, instance.id, sim.id)?;
+                proxy.set_patch_breakpoints(patch_breakpoints);
+                if big_endian {
+                    proxy.set_big_endian(true);
+                }
+                serve_gdb(&mut proxy, listen, reconnect)?;
             }
         }
     }